@@ -0,0 +1,114 @@
+use crate::{ADDRESS_LENGTH, MAIN_ADDRESS_LENGTH};
+use bitcoin::{hashes::Hash as _, Network, PubkeyHash, ScriptBuf};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt::Display, str::FromStr};
+
+/// Sidechain address, written as bs58check.
+#[derive(Debug, Clone, Copy, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Address(pub [u8; ADDRESS_LENGTH]);
+
+impl From<[u8; ADDRESS_LENGTH]> for Address {
+    fn from(bytes: [u8; ADDRESS_LENGTH]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<Address> for [u8; ADDRESS_LENGTH] {
+    fn from(address: Address) -> Self {
+        address.0
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let address = bs58::encode(&self.0).with_check().into_string();
+        write!(f, "{address}")
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseAddressError {
+    #[error("invalid bs58check encoding")]
+    Bs58(#[from] bs58::decode::Error),
+    #[error("address must be {ADDRESS_LENGTH} bytes, got {0}")]
+    WrongLength(usize),
+}
+
+impl FromStr for Address {
+    type Err = ParseAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = bs58::decode(s).with_check(None).into_vec()?;
+        let bytes: [u8; ADDRESS_LENGTH] = bytes
+            .try_into()
+            .map_err(|bytes: Vec<u8>| ParseAddressError::WrongLength(bytes.len()))?;
+        Ok(Self(bytes))
+    }
+}
+
+// bs58check strings in human readable formats, raw bytes otherwise.
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let address = String::deserialize(deserializer)?;
+            address.parse().map_err(serde::de::Error::custom)
+        } else {
+            <[u8; ADDRESS_LENGTH]>::deserialize(deserializer).map(Self)
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MainAddressError {
+    #[error("invalid main address")]
+    Parse(#[from] bitcoin::address::ParseError),
+    #[error("main address `{0}` is not P2PKH")]
+    NotP2pkh(bitcoin::Address),
+}
+
+/// Mainchain P2PKH address, stored as the 20 byte hash of the public key.
+#[derive(
+    Debug, Clone, Copy, Default, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize,
+)]
+pub struct MainAddress(pub [u8; MAIN_ADDRESS_LENGTH]);
+
+impl MainAddress {
+    /// Parse a mainchain address, which must be P2PKH and for `network`.
+    pub fn parse(s: &str, network: Network) -> Result<Self, MainAddressError> {
+        let address = s.parse::<bitcoin::Address<_>>()?.require_network(network)?;
+        Self::try_from(&address)
+    }
+
+    pub fn to_bitcoin_address(&self, network: Network) -> bitcoin::Address {
+        bitcoin::Address::p2pkh(self.pubkey_hash(), network)
+    }
+
+    pub fn script_pubkey(&self) -> ScriptBuf {
+        ScriptBuf::new_p2pkh(&self.pubkey_hash())
+    }
+
+    fn pubkey_hash(&self) -> PubkeyHash {
+        PubkeyHash::from_byte_array(self.0)
+    }
+}
+
+impl TryFrom<&bitcoin::Address> for MainAddress {
+    type Error = MainAddressError;
+
+    fn try_from(address: &bitcoin::Address) -> Result<Self, Self::Error> {
+        let pubkey_hash = address
+            .pubkey_hash()
+            .ok_or_else(|| MainAddressError::NotP2pkh(address.clone()))?;
+        Ok(Self(pubkey_hash.to_byte_array()))
+    }
+}
@@ -0,0 +1,185 @@
+use crate::{Address, Error, Hashable, Output, Result, Transaction, Txid, Wtxid, HASH_LENGTH};
+use bitcoin::{
+    hashes::{hash160, Hash},
+    secp256k1::{self, schnorr::Signature, Keypair, Message, Secp256k1, XOnlyPublicKey},
+};
+use serde::{Deserialize, Serialize};
+
+// The sidechain number is hashed after it, see `Transaction::sighash`.
+const SIGHASH_CONTEXT: &str = "cusf_sidechain_types BIP300 sidechain transaction sighash";
+
+/// BIP340 signature by the key whose hash160 is the address of the output
+/// being spent.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Authorization {
+    pub public_key: XOnlyPublicKey,
+    pub signature: Signature,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthorizedTransaction {
+    pub transaction: Transaction,
+    // One per input, in the same order.
+    pub authorizations: Vec<Authorization>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthorizationError {
+    #[error("{inputs} inputs but {authorizations} authorizations")]
+    WrongAuthorizationCount {
+        inputs: usize,
+        authorizations: usize,
+    },
+    #[error("{inputs} inputs but {spent_outputs} spent outputs")]
+    WrongSpentOutputCount { inputs: usize, spent_outputs: usize },
+    #[error("input {index} spends an output for {expected} but is authorized by {actual}")]
+    WrongAddress {
+        index: usize,
+        expected: Address,
+        actual: Address,
+    },
+    #[error("input {index} has an invalid signature")]
+    InvalidSignature {
+        index: usize,
+        source: secp256k1::Error,
+    },
+}
+
+impl Address {
+    pub fn from_public_key(public_key: &XOnlyPublicKey) -> Self {
+        let hash = <hash160::Hash as Hash>::hash(&public_key.serialize());
+        Self(hash.to_byte_array())
+    }
+}
+
+impl Transaction {
+    /// Message signed by every authorization of this transaction. It commits
+    /// to the sidechain, to all inputs and outputs, and to the outputs spent
+    /// by the inputs, under a context string so that it can't be confused
+    /// with any other hash of the transaction.
+    ///
+    /// Outpoints are counters rather than hashes, so after a reorg the same
+    /// outpoint can name a different output. Committing to the spent outputs
+    /// keeps a signature from being replayed against one of those.
+    pub fn sighash(
+        &self,
+        sidechain_number: u8,
+        spent_outputs: &[Output],
+    ) -> Result<[u8; HASH_LENGTH]> {
+        let mut hasher = blake3::Hasher::new_derive_key(SIGHASH_CONTEXT);
+        hasher.update(&[sidechain_number]);
+        hasher.update(&self.txid()?.0);
+        bincode::serialize_into(&mut hasher, spent_outputs)?;
+        Ok(hasher.finalize().into())
+    }
+}
+
+impl AuthorizedTransaction {
+    pub fn txid(&self) -> Result<Txid> {
+        self.transaction.txid()
+    }
+
+    /// Commits to the authorizations as well as the transaction.
+    pub fn wtxid(&self) -> Result<Wtxid> {
+        self.hash().map(Wtxid)
+    }
+
+    /// Size of the transaction with authorizations, as it counts towards
+    /// `BLOCK_SIZE_LIMIT`.
+    pub fn size(&self) -> Result<u64> {
+        Ok(bincode::serialized_size(self)?)
+    }
+
+    /// Sign every input of `transaction`, with `spent_outputs` and
+    /// `keypairs` in input order.
+    pub fn sign(
+        transaction: Transaction,
+        sidechain_number: u8,
+        spent_outputs: &[Output],
+        keypairs: &[Keypair],
+    ) -> Result<Self> {
+        let inputs = transaction.inputs.len();
+        if keypairs.len() != inputs {
+            return Err(AuthorizationError::WrongAuthorizationCount {
+                inputs,
+                authorizations: keypairs.len(),
+            }
+            .into());
+        }
+        if spent_outputs.len() != inputs {
+            return Err(AuthorizationError::WrongSpentOutputCount {
+                inputs,
+                spent_outputs: spent_outputs.len(),
+            }
+            .into());
+        }
+        let secp = Secp256k1::signing_only();
+        let message = Message::from_digest(transaction.sighash(sidechain_number, spent_outputs)?);
+        let authorizations = keypairs
+            .iter()
+            .map(|keypair| Authorization {
+                public_key: keypair.x_only_public_key().0,
+                signature: secp.sign_schnorr_no_aux_rand(&message, keypair),
+            })
+            .collect();
+        Ok(Self {
+            transaction,
+            authorizations,
+        })
+    }
+}
+
+/// Check that every input of `transaction` is signed by the owner of the
+/// output it spends. `spent_outputs` are the outputs spent by each input, in
+/// input order.
+pub fn verify_authorizations(
+    transaction: &AuthorizedTransaction,
+    sidechain_number: u8,
+    spent_outputs: &[Output],
+) -> Result<()> {
+    let inputs = transaction.transaction.inputs.len();
+    if transaction.authorizations.len() != inputs {
+        return Err(AuthorizationError::WrongAuthorizationCount {
+            inputs,
+            authorizations: transaction.authorizations.len(),
+        }
+        .into());
+    }
+    if spent_outputs.len() != inputs {
+        return Err(AuthorizationError::WrongSpentOutputCount {
+            inputs,
+            spent_outputs: spent_outputs.len(),
+        }
+        .into());
+    }
+    let secp = Secp256k1::verification_only();
+    let message = Message::from_digest(
+        transaction
+            .transaction
+            .sighash(sidechain_number, spent_outputs)?,
+    );
+    for (index, (authorization, spent_output)) in transaction
+        .authorizations
+        .iter()
+        .zip(spent_outputs)
+        .enumerate()
+    {
+        let expected = spent_output.address();
+        let actual = Address::from_public_key(&authorization.public_key);
+        if actual != expected {
+            return Err(AuthorizationError::WrongAddress {
+                index,
+                expected,
+                actual,
+            }
+            .into());
+        }
+        secp.verify_schnorr(
+            &authorization.signature,
+            &message,
+            &authorization.public_key,
+        )
+        .map_err(|source| Error::from(AuthorizationError::InvalidSignature { index, source }))?;
+    }
+    Ok(())
+}
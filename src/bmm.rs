@@ -0,0 +1,49 @@
+//! Blind merged mining: a sidechain block only counts once a mainchain block
+//! commits to its header hash in `MainBlock::bmm_hashes`.
+//!
+//! The sidechain advances by at most one block per mainchain block. If a
+//! mainchain block commits to several headers, the first one in
+//! `bmm_hashes` that builds on the current tip is accepted and the rest are
+//! ignored.
+
+use crate::{BlockHash, Header, HeaderChain, MainBlock, Result};
+
+/// Whether `main_block` commits to `header`.
+pub fn is_bmm_accepted(header: &Header, main_block: &MainBlock) -> Result<bool> {
+    let hash = header.hash()?;
+    Ok(main_block.bmm_hashes.contains(&hash.0))
+}
+
+/// The block that `main_block` adds on top of `tip`, or on top of nothing if
+/// `tip` is `None`. Headers not in `chain` can't be accepted.
+pub fn next_bmm_block(
+    chain: &HeaderChain,
+    tip: Option<BlockHash>,
+    main_block: &MainBlock,
+) -> Option<BlockHash> {
+    let prev = tip.unwrap_or_default();
+    main_block
+        .bmm_hashes
+        .iter()
+        .map(|hash| BlockHash(*hash))
+        .find(|hash| {
+            chain
+                .get(hash)
+                .is_some_and(|header| header.prev_side_block_hash == prev)
+        })
+}
+
+/// The sidechain best chain, from its first block, as accepted by
+/// `main_blocks` in mainchain order.
+pub fn bmm_best_chain<'a>(
+    chain: &HeaderChain,
+    main_blocks: impl IntoIterator<Item = &'a MainBlock>,
+) -> Vec<BlockHash> {
+    let mut best_chain: Vec<BlockHash> = vec![];
+    for main_block in main_blocks {
+        if let Some(hash) = next_bmm_block(chain, best_chain.last().copied(), main_block) {
+            best_chain.push(hash);
+        }
+    }
+    best_chain
+}
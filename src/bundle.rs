@@ -0,0 +1,473 @@
+use crate::{checked_sum, AmountError, OutPoint, Output, HASH_LENGTH};
+use bitcoin::{
+    absolute::LockTime,
+    hashes::Hash as _,
+    opcodes::{all::OP_NOP5, OP_TRUE},
+    policy::MAX_STANDARD_TX_WEIGHT,
+    script::Builder,
+    transaction::Version,
+    Amount, ScriptBuf, Sequence, TxIn, TxOut, Witness,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+// BIP300 OP_DRIVECHAIN, which reuses OP_NOP5.
+const OP_DRIVECHAIN: bitcoin::Opcode = OP_NOP5;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BundleError {
+    #[error("a bundle needs at least one withdrawal")]
+    Empty,
+    #[error("{0} is not a withdrawal")]
+    NotAWithdrawal(OutPoint),
+    #[error("withdrawal {0} is in the bundle more than once")]
+    DuplicateWithdrawal(OutPoint),
+    #[error("bundle value is invalid")]
+    Amount(#[from] AmountError),
+    #[error("treasury has {treasury} sats but the bundle needs {required}")]
+    InsufficientTreasury { treasury: u64, required: u64 },
+}
+
+/// Withdrawals paid out together by one mainchain transaction.
+///
+/// Withdrawals are kept sorted by outpoint, so every node that bundles the
+/// same withdrawals builds byte-identical transactions and the same M6ID.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedBundle")]
+pub struct WithdrawalBundle {
+    withdrawals: Vec<(OutPoint, Output)>,
+}
+
+#[derive(Deserialize)]
+struct UncheckedBundle {
+    withdrawals: Vec<(OutPoint, Output)>,
+}
+
+impl TryFrom<UncheckedBundle> for WithdrawalBundle {
+    type Error = BundleError;
+
+    fn try_from(bundle: UncheckedBundle) -> Result<Self, Self::Error> {
+        Self::new(bundle.withdrawals)
+    }
+}
+
+impl WithdrawalBundle {
+    pub fn new(
+        withdrawals: impl IntoIterator<Item = (OutPoint, Output)>,
+    ) -> Result<Self, BundleError> {
+        let mut withdrawals: Vec<(OutPoint, Output)> = withdrawals.into_iter().collect();
+        if withdrawals.is_empty() {
+            return Err(BundleError::Empty);
+        }
+        let mut outpoints = HashSet::new();
+        for (outpoint, output) in &withdrawals {
+            let Output::Withdrawal { .. } = output else {
+                return Err(BundleError::NotAWithdrawal(outpoint.clone()));
+            };
+            output.checked_total_value()?;
+            if !outpoints.insert(outpoint) {
+                return Err(BundleError::DuplicateWithdrawal(outpoint.clone()));
+            }
+        }
+        withdrawals.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let bundle = Self { withdrawals };
+        bundle.value()?;
+        bundle.fee()?;
+        Ok(bundle)
+    }
+
+    pub fn withdrawals(&self) -> &[(OutPoint, Output)] {
+        &self.withdrawals
+    }
+
+    pub fn outpoints(&self) -> impl Iterator<Item = &OutPoint> {
+        self.withdrawals.iter().map(|(outpoint, _)| outpoint)
+    }
+
+    /// Total paid out to mainchain destinations.
+    pub fn value(&self) -> Result<u64, AmountError> {
+        checked_sum(self.withdrawals.iter().map(|(_, output)| match output {
+            Output::Withdrawal { value, .. } => Ok(*value),
+            Output::Regular { .. } => Ok(0),
+        }))
+    }
+
+    /// Total mainchain fee paid by the bundle transaction.
+    pub fn fee(&self) -> Result<u64, AmountError> {
+        checked_sum(
+            self.withdrawals
+                .iter()
+                .map(|(_, output)| Ok(withdrawal_fee(output))),
+        )
+    }
+
+    fn payouts(&self) -> impl Iterator<Item = TxOut> + '_ {
+        self.withdrawals
+            .iter()
+            .filter_map(|(_, output)| payout(output))
+    }
+
+    /// The bundle as the sidechain commits to it: no inputs, an OP_RETURN
+    /// output with the total fee as 8 big endian bytes, then one payout per
+    /// withdrawal.
+    ///
+    /// This is how the enforcer blinds an M6 in `m6_to_id`, since the
+    /// treasury UTXO isn't known in advance: the inputs are cleared and the
+    /// treasury output at index 0 is replaced by the fee output.
+    pub fn blinded_transaction(&self) -> Result<bitcoin::Transaction, BundleError> {
+        let fee_output = TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::new_op_return(self.fee()?.to_be_bytes()),
+        };
+        Ok(bitcoin::Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: std::iter::once(fee_output).chain(self.payouts()).collect(),
+        })
+    }
+
+    /// BIP300 M6ID: the txid of the blinded transaction.
+    pub fn m6id(&self) -> Result<[u8; HASH_LENGTH], BundleError> {
+        Ok(self.blinded_transaction()?.compute_txid().to_byte_array())
+    }
+
+    /// The M6 transaction, spending the sidechain's treasury UTXO. The first
+    /// output returns the change to the treasury, followed by the payouts.
+    /// The difference between inputs and outputs is the bundle fee.
+    pub fn transaction(
+        &self,
+        sidechain_number: u8,
+        treasury: bitcoin::OutPoint,
+        treasury_value: Amount,
+    ) -> Result<bitcoin::Transaction, BundleError> {
+        let required = self
+            .value()?
+            .checked_add(self.fee()?)
+            .ok_or(AmountError::Overflow)?;
+        let change = treasury_value.to_sat().checked_sub(required).ok_or(
+            BundleError::InsufficientTreasury {
+                treasury: treasury_value.to_sat(),
+                required,
+            },
+        )?;
+        Ok(m6_transaction(
+            sidechain_number,
+            treasury,
+            Amount::from_sat(change),
+            self.payouts(),
+        ))
+    }
+
+    /// Weight of the M6 transaction, in weight units. It doesn't depend on
+    /// the treasury UTXO.
+    pub fn weight(&self) -> u64 {
+        m6_transaction(0, bitcoin::OutPoint::null(), Amount::ZERO, self.payouts())
+            .weight()
+            .to_wu()
+    }
+
+    /// Pick withdrawals for the next bundle, highest fee first, within
+    /// `limits`. Withdrawals with equal fees are taken in outpoint order.
+    /// A withdrawal that doesn't fit is skipped, and smaller ones after it
+    /// may still be taken.
+    pub fn select(
+        pending: impl IntoIterator<Item = (OutPoint, Output)>,
+        limits: &BundleLimits,
+    ) -> Result<BundleSelection, BundleError> {
+        let mut pending: Vec<(OutPoint, Output)> = pending.into_iter().collect();
+        if let Some((outpoint, _)) = pending
+            .iter()
+            .find(|(_, output)| !matches!(output, Output::Withdrawal { .. }))
+        {
+            return Err(BundleError::NotAWithdrawal(outpoint.clone()));
+        }
+        pending.sort_by(|(a_outpoint, a), (b_outpoint, b)| {
+            withdrawal_fee(b)
+                .cmp(&withdrawal_fee(a))
+                .then_with(|| a_outpoint.cmp(b_outpoint))
+        });
+
+        let base = m6_transaction(0, bitcoin::OutPoint::null(), Amount::ZERO, [])
+            .weight()
+            .to_wu();
+        let mut outputs_weight = 0;
+        let mut selected = vec![];
+        let mut carryover = vec![];
+        for (outpoint, output) in pending {
+            let output_weight = payout(&output).map_or(0, |payout| payout.weight().to_wu());
+            // The output count is a compact size, which grows with the count.
+            let count_weight = 4 * (compact_size_len(selected.len() + 2) - 1);
+            let weight = base + outputs_weight + output_weight + count_weight;
+            if selected.len() < limits.max_withdrawals && weight <= limits.max_weight {
+                outputs_weight += output_weight;
+                selected.push((outpoint, output));
+            } else {
+                carryover.push((outpoint, output));
+            }
+        }
+        let bundle = if selected.is_empty() {
+            None
+        } else {
+            Some(Self::new(selected)?)
+        };
+        Ok(BundleSelection { bundle, carryover })
+    }
+}
+
+/// The outcome of `WithdrawalBundle::select`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BundleSelection {
+    /// `None` if no withdrawal fit within the limits.
+    pub bundle: Option<WithdrawalBundle>,
+    /// Withdrawals left over for later bundles, highest fee first.
+    pub carryover: Vec<(OutPoint, Output)>,
+}
+
+/// Limits on the size of a single bundle.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BundleLimits {
+    /// Maximum weight of the M6 transaction, in weight units.
+    pub max_weight: u64,
+    pub max_withdrawals: usize,
+}
+
+impl Default for BundleLimits {
+    fn default() -> Self {
+        Self {
+            max_weight: MAX_STANDARD_TX_WEIGHT.into(),
+            max_withdrawals: 6000,
+        }
+    }
+}
+
+fn withdrawal_fee(output: &Output) -> u64 {
+    match output {
+        Output::Withdrawal { fee, .. } => *fee,
+        Output::Regular { .. } => 0,
+    }
+}
+
+fn payout(output: &Output) -> Option<TxOut> {
+    match output {
+        Output::Withdrawal {
+            main_address,
+            value,
+            ..
+        } => Some(TxOut {
+            value: Amount::from_sat(*value),
+            script_pubkey: main_address.script_pubkey(),
+        }),
+        Output::Regular { .. } => None,
+    }
+}
+
+fn compact_size_len(n: usize) -> u64 {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+fn m6_transaction(
+    sidechain_number: u8,
+    treasury: bitcoin::OutPoint,
+    change: Amount,
+    payouts: impl IntoIterator<Item = TxOut>,
+) -> bitcoin::Transaction {
+    let treasury_output = TxOut {
+        value: change,
+        script_pubkey: Builder::new()
+            .push_opcode(OP_DRIVECHAIN)
+            .push_slice([sidechain_number])
+            .push_opcode(OP_TRUE)
+            .into_script(),
+    };
+    bitcoin::Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: treasury,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: std::iter::once(treasury_output).chain(payouts).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, WithdrawalDestination, ADDRESS_LENGTH};
+    use bitcoin::{hex::DisplayHex as _, opcodes::all::OP_RETURN};
+
+    fn withdrawal(transaction_number: u64, value: u64, fee: u64) -> (OutPoint, Output) {
+        (
+            OutPoint::Regular {
+                transaction_number,
+                output_number: 0,
+            },
+            Output::Withdrawal {
+                address: Address::from([0; ADDRESS_LENGTH]),
+                main_address: WithdrawalDestination::P2wpkh([transaction_number as u8; 20]),
+                value,
+                fee,
+            },
+        )
+    }
+
+    // The enforcer's `m6_to_id`: clear the inputs of the M6 and replace its
+    // treasury output with the fee it pays, as 8 big endian bytes.
+    fn m6_to_id(m6: &bitcoin::Transaction, treasury_value: Amount) -> [u8; HASH_LENGTH] {
+        let mut m6 = m6.clone();
+        let outputs: Amount = m6.output.iter().map(|output| output.value).sum();
+        let fee = (treasury_value - outputs).to_sat();
+        m6.input.clear();
+        m6.output[0] = TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::new_op_return(fee.to_be_bytes()),
+        };
+        m6.compute_txid().to_byte_array()
+    }
+
+    #[test]
+    fn m6id_matches_blinded_m6() {
+        let bundle = WithdrawalBundle::new([
+            withdrawal(2, 50_000, 1_000),
+            withdrawal(1, 20_000, 300),
+            withdrawal(3, 70_000, 0),
+        ])
+        .unwrap();
+        let treasury = bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_byte_array([7; 32]),
+            vout: 1,
+        };
+        let treasury_value = Amount::from_sat(1_000_000);
+        let m6 = bundle.transaction(3, treasury, treasury_value).unwrap();
+        assert_eq!(bundle.m6id().unwrap(), m6_to_id(&m6, treasury_value));
+
+        let blinded = bundle.blinded_transaction().unwrap();
+        let fee_output = &blinded.output[0].script_pubkey;
+        assert_eq!(fee_output.as_bytes()[0], OP_RETURN.to_u8());
+        assert_eq!(fee_output.as_bytes()[2..], 1_300u64.to_be_bytes());
+        // Pinned, in internal byte order, so the bundle encoding can't change
+        // unnoticed.
+        assert_eq!(
+            bundle.m6id().unwrap().as_hex().to_string(),
+            "6f7f8cad3b277b31f54c330ba0aeacc7abd78d13b847dfa8ac08af10f1846f23"
+        );
+    }
+
+    fn outpoints(withdrawals: &[(OutPoint, Output)]) -> Vec<u64> {
+        withdrawals
+            .iter()
+            .map(|(outpoint, _)| match outpoint {
+                OutPoint::Regular {
+                    transaction_number, ..
+                } => *transaction_number,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    fn pending() -> Vec<(OutPoint, Output)> {
+        vec![
+            withdrawal(1, 10_000, 5),
+            withdrawal(4, 10_000, 1),
+            withdrawal(3, 10_000, 10),
+            withdrawal(2, 10_000, 10),
+        ]
+    }
+
+    #[test]
+    fn select_orders_by_fee_then_outpoint() {
+        let limits = BundleLimits {
+            max_withdrawals: 2,
+            ..BundleLimits::default()
+        };
+        let selection = WithdrawalBundle::select(pending(), &limits).unwrap();
+        let bundle = selection.bundle.unwrap();
+        assert_eq!(outpoints(bundle.withdrawals()), [2, 3]);
+        assert_eq!(outpoints(&selection.carryover), [1, 4]);
+
+        let limits = BundleLimits {
+            max_withdrawals: 0,
+            ..BundleLimits::default()
+        };
+        let selection = WithdrawalBundle::select(pending(), &limits).unwrap();
+        assert_eq!(selection.bundle, None);
+        assert_eq!(outpoints(&selection.carryover), [2, 3, 1, 4]);
+    }
+
+    #[test]
+    fn select_weight_boundary() {
+        let weight = WithdrawalBundle::new(pending()).unwrap().weight();
+        let select = |max_weight| {
+            let limits = BundleLimits {
+                max_weight,
+                ..BundleLimits::default()
+            };
+            WithdrawalBundle::select(pending(), &limits).unwrap()
+        };
+
+        for max_weight in [weight, weight + 1] {
+            let selection = select(max_weight);
+            assert_eq!(
+                outpoints(selection.bundle.unwrap().withdrawals()),
+                [1, 2, 3, 4]
+            );
+            assert!(selection.carryover.is_empty());
+        }
+
+        let selection = select(weight - 1);
+        let bundle = selection.bundle.unwrap();
+        assert_eq!(outpoints(bundle.withdrawals()), [1, 2, 3]);
+        assert!(bundle.weight() < weight);
+        assert_eq!(outpoints(&selection.carryover), [4]);
+
+        // A withdrawal too heavy to fit is skipped for lighter ones after it.
+        let mut heavy = pending();
+        heavy.push((
+            OutPoint::Regular {
+                transaction_number: 5,
+                output_number: 0,
+            },
+            Output::Withdrawal {
+                address: Address::from([0; ADDRESS_LENGTH]),
+                main_address: WithdrawalDestination::Script(ScriptBuf::from_bytes(vec![0x51; 100])),
+                value: 10_000,
+                fee: 7,
+            },
+        ));
+        let limits = BundleLimits {
+            max_weight: weight,
+            ..BundleLimits::default()
+        };
+        let selection = WithdrawalBundle::select(heavy, &limits).unwrap();
+        assert_eq!(
+            outpoints(selection.bundle.unwrap().withdrawals()),
+            [1, 2, 3, 4]
+        );
+        assert_eq!(outpoints(&selection.carryover), [5]);
+    }
+
+    #[test]
+    fn select_rejects_regular_outputs() {
+        let mut pending = pending();
+        let outpoint = OutPoint::Deposit { sequence_number: 0 };
+        pending.push((
+            outpoint.clone(),
+            Output::Regular {
+                address: Address::from([0; ADDRESS_LENGTH]),
+                value: 1,
+            },
+        ));
+        assert!(matches!(
+            WithdrawalBundle::select(pending, &BundleLimits::default()),
+            Err(BundleError::NotAWithdrawal(o)) if o == outpoint
+        ));
+    }
+}
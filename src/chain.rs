@@ -0,0 +1,241 @@
+use crate::{BlockHash, Header, Result};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, thiserror::Error)]
+pub enum HeaderChainError {
+    #[error("header builds on {0}, which is not known")]
+    UnknownPrev(BlockHash),
+    #[error("header {0} is not known")]
+    UnknownHeader(BlockHash),
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    header: Header,
+    height: u32,
+    // Order the header was accepted in, to break ties between tips.
+    sequence: u64,
+}
+
+/// Every header accepted so far, including those on forks.
+///
+/// A header whose `prev_side_block_hash` is all zeroes starts a chain at
+/// height 0. Any other header must build on a header that is already known,
+/// and is one higher than it.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderChain {
+    entries: HashMap<BlockHash, Entry>,
+    tips: HashSet<BlockHash>,
+}
+
+impl HeaderChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept a header, returning its hash and height. Accepting a header
+    /// that is already known does nothing.
+    pub fn insert(&mut self, header: Header) -> Result<(BlockHash, u32)> {
+        let hash = header.hash()?;
+        if let Some(entry) = self.entries.get(&hash) {
+            return Ok((hash, entry.height));
+        }
+        let prev = header.prev_side_block_hash;
+        let height = if prev == BlockHash::default() {
+            0
+        } else {
+            let prev_entry = self
+                .entries
+                .get(&prev)
+                .ok_or(HeaderChainError::UnknownPrev(prev))?;
+            prev_entry.height + 1
+        };
+        self.tips.remove(&prev);
+        self.tips.insert(hash);
+        let sequence = self.entries.len() as u64;
+        self.entries.insert(
+            hash,
+            Entry {
+                header,
+                height,
+                sequence,
+            },
+        );
+        Ok((hash, height))
+    }
+
+    pub fn get(&self, hash: &BlockHash) -> Option<&Header> {
+        self.entries.get(hash).map(|entry| &entry.header)
+    }
+
+    pub fn height(&self, hash: &BlockHash) -> Option<u32> {
+        self.entries.get(hash).map(|entry| entry.height)
+    }
+
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    /// Headers that nothing builds on yet, one per fork.
+    pub fn tips(&self) -> impl Iterator<Item = &BlockHash> {
+        self.tips.iter()
+    }
+
+    /// The highest tip, or the first one accepted if several are equally
+    /// high.
+    pub fn best_tip(&self) -> Option<BlockHash> {
+        self.tips
+            .iter()
+            .map(|hash| (hash, &self.entries[hash]))
+            .max_by_key(|(_, entry)| (entry.height, std::cmp::Reverse(entry.sequence)))
+            .map(|(hash, _)| *hash)
+    }
+
+    /// The header at `height` on the chain ending at `tip`, or `None` if
+    /// `height` is above `tip`.
+    pub fn ancestor(
+        &self,
+        tip: &BlockHash,
+        height: u32,
+    ) -> Result<Option<BlockHash>, HeaderChainError> {
+        let mut hash = *tip;
+        let mut entry = self
+            .entries
+            .get(&hash)
+            .ok_or(HeaderChainError::UnknownHeader(hash))?;
+        if height > entry.height {
+            return Ok(None);
+        }
+        while entry.height > height {
+            hash = entry.header.prev_side_block_hash;
+            entry = &self.entries[&hash];
+        }
+        Ok(Some(hash))
+    }
+
+    /// The highest header that both chains share, or `None` if they start
+    /// from different headers at height 0.
+    pub fn common_ancestor(
+        &self,
+        a: &BlockHash,
+        b: &BlockHash,
+    ) -> Result<Option<BlockHash>, HeaderChainError> {
+        let height_a = self.height(a).ok_or(HeaderChainError::UnknownHeader(*a))?;
+        let height_b = self.height(b).ok_or(HeaderChainError::UnknownHeader(*b))?;
+        let height = height_a.min(height_b);
+        let (Some(mut a), Some(mut b)) = (self.ancestor(a, height)?, self.ancestor(b, height)?)
+        else {
+            return Ok(None);
+        };
+        while a != b {
+            let (entry_a, entry_b) = (&self.entries[&a], &self.entries[&b]);
+            if entry_a.height == 0 {
+                return Ok(None);
+            }
+            a = entry_a.header.prev_side_block_hash;
+            b = entry_b.header.prev_side_block_hash;
+        }
+        Ok(Some(a))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, HASH_LENGTH};
+
+    fn header(prev: BlockHash, nonce: u8) -> Header {
+        Header {
+            prev_side_block_hash: prev,
+            merkle_root: [nonce; HASH_LENGTH],
+            witness_merkle_root: [0; HASH_LENGTH],
+        }
+    }
+
+    // Insert a chain of `length` headers on top of `prev`, returning their
+    // hashes.
+    fn extend(chain: &mut HeaderChain, prev: BlockHash, nonce: u8, length: u32) -> Vec<BlockHash> {
+        let mut hashes = vec![];
+        let mut prev = prev;
+        for _ in 0..length {
+            let (hash, _) = chain.insert(header(prev, nonce)).unwrap();
+            hashes.push(hash);
+            prev = hash;
+        }
+        hashes
+    }
+
+    #[test]
+    fn heights_and_unknown_prev() {
+        let mut chain = HeaderChain::new();
+        let hashes = extend(&mut chain, BlockHash::default(), 0, 3);
+        assert_eq!(chain.height(&hashes[0]), Some(0));
+        assert_eq!(chain.height(&hashes[2]), Some(2));
+        assert_eq!(chain.insert(header(hashes[0], 0)).unwrap(), (hashes[1], 1));
+
+        let unknown = BlockHash([9; HASH_LENGTH]);
+        assert!(matches!(
+            chain.insert(header(unknown, 0)),
+            Err(Error::HeaderChain(HeaderChainError::UnknownPrev(prev))) if prev == unknown
+        ));
+    }
+
+    #[test]
+    fn best_tip_prefers_height_then_first_seen() {
+        let mut chain = HeaderChain::new();
+        let genesis = extend(&mut chain, BlockHash::default(), 0, 1)[0];
+        let a = extend(&mut chain, genesis, 1, 2);
+        let b = extend(&mut chain, genesis, 2, 2);
+        assert_eq!(chain.tips().count(), 2);
+        assert_eq!(chain.best_tip(), Some(a[1]));
+
+        let b = extend(&mut chain, b[1], 2, 1);
+        assert_eq!(chain.best_tip(), Some(b[0]));
+    }
+
+    #[test]
+    fn ancestors_across_forks() {
+        let mut chain = HeaderChain::new();
+        let main = extend(&mut chain, BlockHash::default(), 0, 5);
+        let fork = extend(&mut chain, main[1], 1, 4);
+
+        assert_eq!(chain.ancestor(&fork[3], 1).unwrap(), Some(main[1]));
+        assert_eq!(chain.ancestor(&fork[3], 2).unwrap(), Some(fork[0]));
+        assert_eq!(chain.ancestor(&fork[3], 5).unwrap(), Some(fork[3]));
+        assert_eq!(chain.ancestor(&fork[3], 6).unwrap(), None);
+        assert_eq!(chain.ancestor(&main[4], 0).unwrap(), Some(main[0]));
+
+        assert_eq!(
+            chain.common_ancestor(&main[4], &fork[3]).unwrap(),
+            Some(main[1])
+        );
+        assert_eq!(
+            chain.common_ancestor(&fork[0], &main[4]).unwrap(),
+            Some(main[1])
+        );
+        assert_eq!(
+            chain.common_ancestor(&main[3], &main[1]).unwrap(),
+            Some(main[1])
+        );
+        assert_eq!(
+            chain.common_ancestor(&main[2], &main[2]).unwrap(),
+            Some(main[2])
+        );
+    }
+
+    #[test]
+    fn separate_genesis_headers_have_no_common_ancestor() {
+        let mut chain = HeaderChain::new();
+        let a = extend(&mut chain, BlockHash::default(), 1, 3);
+        let b = extend(&mut chain, BlockHash::default(), 2, 2);
+        assert_eq!(chain.common_ancestor(&a[2], &b[1]).unwrap(), None);
+        assert_eq!(chain.common_ancestor(&a[0], &b[0]).unwrap(), None);
+
+        let unknown = BlockHash([9; HASH_LENGTH]);
+        assert!(matches!(
+            chain.common_ancestor(&a[0], &unknown),
+            Err(HeaderChainError::UnknownHeader(hash)) if hash == unknown
+        ));
+        assert!(chain.ancestor(&unknown, 0).is_err());
+    }
+}
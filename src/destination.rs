@@ -0,0 +1,200 @@
+use crate::MainAddress;
+use bitcoin::{
+    hashes::Hash as _, opcodes::all::OP_PUSHNUM_1, script::Builder, Network, Script, ScriptBuf,
+    WPubkeyHash, WScriptHash,
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, str::FromStr};
+
+// Large enough for every standard output script.
+pub const MAX_DESTINATION_SCRIPT_LENGTH: usize = 128;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DestinationError {
+    #[error("invalid mainchain address")]
+    Address(#[from] bitcoin::address::ParseError),
+    #[error("invalid script hex")]
+    ScriptHex(#[from] bitcoin::hex::HexToBytesError),
+    #[error("script is {0} bytes, more than {MAX_DESTINATION_SCRIPT_LENGTH}")]
+    ScriptTooLong(usize),
+    #[error("script has a dedicated destination type and must use it")]
+    NonCanonicalScript,
+}
+
+/// Mainchain output that a withdrawal pays to.
+///
+/// Standard output types are stored as their hash or key, anything else as a
+/// raw scriptPubKey of at most [`MAX_DESTINATION_SCRIPT_LENGTH`] bytes. A
+/// script that matches one of the standard types is rejected, so that every
+/// destination has exactly one encoding.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedDestination")]
+pub enum WithdrawalDestination {
+    P2pkh(MainAddress),
+    P2wpkh([u8; 20]),
+    P2wsh([u8; 32]),
+    // Tweaked x-only output key.
+    P2tr([u8; 32]),
+    Script(ScriptBuf),
+}
+
+impl WithdrawalDestination {
+    pub fn from_script_pubkey(script: &Script) -> Result<Self, DestinationError> {
+        let bytes = script.as_bytes();
+        let destination = if script.is_p2pkh() {
+            bytes[3..23]
+                .try_into()
+                .ok()
+                .map(|hash| Self::P2pkh(MainAddress(hash)))
+        } else if script.is_p2wpkh() {
+            bytes[2..].try_into().ok().map(Self::P2wpkh)
+        } else if script.is_p2wsh() {
+            bytes[2..].try_into().ok().map(Self::P2wsh)
+        } else if script.is_p2tr() {
+            bytes[2..].try_into().ok().map(Self::P2tr)
+        } else {
+            None
+        };
+        match destination {
+            Some(destination) => Ok(destination),
+            None if script.len() > MAX_DESTINATION_SCRIPT_LENGTH => {
+                Err(DestinationError::ScriptTooLong(script.len()))
+            }
+            None => Ok(Self::Script(script.to_owned())),
+        }
+    }
+
+    /// Parse a mainchain address for `network`.
+    pub fn parse(s: &str, network: Network) -> Result<Self, DestinationError> {
+        let address = s.parse::<bitcoin::Address<_>>()?.require_network(network)?;
+        Self::from_script_pubkey(&address.script_pubkey())
+    }
+
+    pub fn script_pubkey(&self) -> ScriptBuf {
+        match self {
+            Self::P2pkh(main_address) => main_address.script_pubkey(),
+            Self::P2wpkh(hash) => ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array(*hash)),
+            Self::P2wsh(hash) => ScriptBuf::new_p2wsh(&WScriptHash::from_byte_array(*hash)),
+            Self::P2tr(output_key) => Builder::new()
+                .push_opcode(OP_PUSHNUM_1)
+                .push_slice(output_key)
+                .into_script(),
+            Self::Script(script) => script.clone(),
+        }
+    }
+
+    /// `None` if the destination is a script with no address form.
+    pub fn to_bitcoin_address(&self, network: Network) -> Option<bitcoin::Address> {
+        bitcoin::Address::from_script(&self.script_pubkey(), network).ok()
+    }
+}
+
+impl From<MainAddress> for WithdrawalDestination {
+    fn from(main_address: MainAddress) -> Self {
+        Self::P2pkh(main_address)
+    }
+}
+
+impl TryFrom<&bitcoin::Address> for WithdrawalDestination {
+    type Error = DestinationError;
+
+    fn try_from(address: &bitcoin::Address) -> Result<Self, Self::Error> {
+        Self::from_script_pubkey(&address.script_pubkey())
+    }
+}
+
+// Mainnet addresses for the standard types, `script:<hex>` for anything else.
+// Written as `script:<hex>` rather than an address, which would depend on
+// the mainchain network. Use `to_bitcoin_address` to show an address.
+impl Display for WithdrawalDestination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "script:{}", self.script_pubkey().to_hex_string())
+    }
+}
+
+impl FromStr for WithdrawalDestination {
+    type Err = DestinationError;
+
+    /// Parses `script:<hex>`, or an address for any network.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("script:") {
+            Some(script) => Self::from_script_pubkey(&ScriptBuf::from_hex(script)?),
+            None => {
+                let address = s.parse::<bitcoin::Address<_>>()?.assume_checked();
+                Self::from_script_pubkey(&address.script_pubkey())
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+enum UncheckedDestination {
+    P2pkh(MainAddress),
+    P2wpkh([u8; 20]),
+    P2wsh([u8; 32]),
+    P2tr([u8; 32]),
+    Script(ScriptBuf),
+}
+
+impl TryFrom<UncheckedDestination> for WithdrawalDestination {
+    type Error = DestinationError;
+
+    fn try_from(destination: UncheckedDestination) -> Result<Self, Self::Error> {
+        match destination {
+            UncheckedDestination::P2pkh(main_address) => Ok(Self::P2pkh(main_address)),
+            UncheckedDestination::P2wpkh(hash) => Ok(Self::P2wpkh(hash)),
+            UncheckedDestination::P2wsh(hash) => Ok(Self::P2wsh(hash)),
+            UncheckedDestination::P2tr(output_key) => Ok(Self::P2tr(output_key)),
+            UncheckedDestination::Script(script) => match Self::from_script_pubkey(&script)? {
+                Self::Script(script) => Ok(Self::Script(script)),
+                _ => Err(DestinationError::NonCanonicalScript),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn destinations() -> Vec<WithdrawalDestination> {
+        vec![
+            WithdrawalDestination::P2pkh(MainAddress([1; 20])),
+            WithdrawalDestination::P2wpkh([2; 20]),
+            WithdrawalDestination::P2wsh([3; 32]),
+            WithdrawalDestination::P2tr([4; 32]),
+            WithdrawalDestination::Script(ScriptBuf::from_bytes(vec![0x6a, 0x01, 0x05])),
+        ]
+    }
+
+    #[test]
+    fn text_round_trip() {
+        for destination in destinations() {
+            let s = destination.to_string();
+            assert!(s.starts_with("script:"));
+            assert_eq!(s.parse::<WithdrawalDestination>().unwrap(), destination);
+        }
+    }
+
+    #[test]
+    fn parses_addresses_for_any_network() {
+        for network in [
+            Network::Bitcoin,
+            Network::Testnet,
+            Network::Signet,
+            Network::Regtest,
+        ] {
+            for destination in destinations() {
+                let Some(address) = destination.to_bitcoin_address(network) else {
+                    continue;
+                };
+                let s = address.to_string();
+                assert_eq!(s.parse::<WithdrawalDestination>().unwrap(), destination);
+                assert_eq!(
+                    WithdrawalDestination::parse(&s, network).unwrap(),
+                    destination
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use crate::{
+    AmountError, AuthorizationError, BlockValidationError, BundleError, DestinationError,
+    HeaderChainError, MainAddressError, ParseAddressError, ParseOutPointError, ParseOutputError,
+    TrackerError, TransactionError, UtxoError,
+};
+
+/// Returned by every operation that can fail to encode, and by those that
+/// combine checks from several modules. The module errors only describe what
+/// was wrong with the data.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("encoding failed")]
+    Encoding(#[from] bincode::Error),
+    #[error(transparent)]
+    BlockValidation(#[from] BlockValidationError),
+    #[error(transparent)]
+    Transaction(#[from] TransactionError),
+    #[error(transparent)]
+    Authorization(#[from] AuthorizationError),
+    #[error(transparent)]
+    Utxo(#[from] UtxoError),
+    #[error(transparent)]
+    HeaderChain(#[from] HeaderChainError),
+    #[error(transparent)]
+    Bundle(#[from] BundleError),
+    #[error(transparent)]
+    Tracker(#[from] TrackerError),
+    #[error(transparent)]
+    ParseOutPoint(#[from] ParseOutPointError),
+    #[error(transparent)]
+    ParseOutput(#[from] ParseOutputError),
+    #[error(transparent)]
+    ParseAddress(#[from] ParseAddressError),
+    #[error("invalid hash")]
+    ParseHash(#[from] bitcoin::hex::HexToArrayError),
+    #[error(transparent)]
+    MainAddress(#[from] MainAddressError),
+    #[error(transparent)]
+    Destination(#[from] DestinationError),
+    #[error(transparent)]
+    Amount(#[from] AmountError),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,80 @@
+use crate::HASH_LENGTH;
+use bitcoin::hex::{DisplayHex as _, FromHex as _, HexToArrayError};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt::Display, str::FromStr};
+
+// Hex strings in human readable formats, raw bytes otherwise, so that the
+// bincode encoding (and so every hash) is the same as for a bare array.
+macro_rules! hash_newtype {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+        pub struct $name(pub [u8; HASH_LENGTH]);
+
+        impl From<[u8; HASH_LENGTH]> for $name {
+            fn from(bytes: [u8; HASH_LENGTH]) -> Self {
+                Self(bytes)
+            }
+        }
+
+        impl From<$name> for [u8; HASH_LENGTH] {
+            fn from(hash: $name) -> Self {
+                hash.0
+            }
+        }
+
+        impl std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}({self})", stringify!($name))
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}", self.0.as_hex())
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = HexToArrayError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                <[u8; HASH_LENGTH]>::from_hex(s).map(Self)
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                if serializer.is_human_readable() {
+                    serializer.collect_str(self)
+                } else {
+                    self.0.serialize(serializer)
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                if deserializer.is_human_readable() {
+                    let hash = String::deserialize(deserializer)?;
+                    hash.parse().map_err(serde::de::Error::custom)
+                } else {
+                    <[u8; HASH_LENGTH]>::deserialize(deserializer).map(Self)
+                }
+            }
+        }
+    };
+}
+
+hash_newtype!(
+    /// Hash of a `Header`, see `Header::hash`.
+    BlockHash
+);
+hash_newtype!(
+    /// Hash of a `Transaction`, see `Transaction::txid`.
+    Txid
+);
+hash_newtype!(
+    /// Hash of an `AuthorizedTransaction`, see `AuthorizedTransaction::wtxid`.
+    Wtxid
+);
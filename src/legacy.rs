@@ -0,0 +1,57 @@
+//! Layouts that have since been replaced, kept so that data encoded with them
+//! can still be decoded and migrated.
+//!
+//! Migrating changes the encoding, and so the hash, of every converted value.
+
+use crate::{Address, MainAddress, OutPoint};
+use serde::{Deserialize, Serialize};
+
+/// [`crate::Output`] from before withdrawals could pay to any
+/// [`crate::WithdrawalDestination`], when the main address was always P2PKH.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Output {
+    Regular {
+        address: Address,
+        value: u64,
+    },
+    Withdrawal {
+        address: Address,
+        main_address: MainAddress,
+        value: u64,
+        fee: u64,
+    },
+}
+
+impl From<Output> for crate::Output {
+    fn from(output: Output) -> Self {
+        match output {
+            Output::Regular { address, value } => Self::Regular { address, value },
+            Output::Withdrawal {
+                address,
+                main_address,
+                value,
+                fee,
+            } => Self::Withdrawal {
+                address,
+                main_address: main_address.into(),
+                value,
+                fee,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    pub inputs: Vec<OutPoint>,
+    pub outputs: Vec<Output>,
+}
+
+impl From<Transaction> for crate::Transaction {
+    fn from(transaction: Transaction) -> Self {
+        Self {
+            inputs: transaction.inputs,
+            outputs: transaction.outputs.into_iter().map(Into::into).collect(),
+        }
+    }
+}
@@ -1,13 +1,49 @@
 use serde::{Deserialize, Serialize};
-use std::fmt::Display;
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    num::{IntErrorKind, ParseIntError},
+    str::FromStr,
+};
 
+mod address;
+mod authorization;
+pub mod bmm;
+mod bundle;
+mod chain;
+mod destination;
+mod error;
+mod hashes;
+pub mod legacy;
+pub mod merkle;
+mod tracker;
+mod utxo;
+mod validation;
+
+pub use address::{Address, MainAddress, MainAddressError, ParseAddressError};
+pub use authorization::{
+    verify_authorizations, Authorization, AuthorizationError, AuthorizedTransaction,
+};
+pub use bundle::{BundleError, BundleLimits, BundleSelection, WithdrawalBundle};
+pub use chain::{HeaderChain, HeaderChainError};
+pub use destination::{DestinationError, WithdrawalDestination, MAX_DESTINATION_SCRIPT_LENGTH};
+pub use error::{Error, Result};
+pub use hashes::{BlockHash, Txid, Wtxid};
+pub use tracker::{BundleStatus, TrackerError, WithdrawalStatus, WithdrawalTracker};
+pub use utxo::{BlockUndo, MainBlockUndo, UtxoError, UtxoSet, UtxoView};
+pub use validation::{BlockValidationError, ConsensusParams};
+
+// Measured as the bincode encoding of `Block`, see `Block::serialized_size`.
 pub const BLOCK_SIZE_LIMIT: usize = 1024 * 1024; // 1 MB by default.
 
+// `Amount::to_sat` isn't const, so this is an `Amount` rather than a u64.
+pub const MAX_MONEY: bitcoin::Amount = bitcoin::Amount::MAX_MONEY;
+
 pub const MAIN_ADDRESS_LENGTH: usize = 20;
 pub const ADDRESS_LENGTH: usize = 20;
 pub const HASH_LENGTH: usize = 32;
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub enum OutPoint {
     Regular {
         transaction_number: u64,
@@ -38,30 +74,133 @@ impl Display for OutPoint {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, thiserror::Error)]
+pub enum ParseOutPointError {
+    #[error("unknown outpoint prefix `{0}`, expected `r`, `c` or `d`")]
+    BadPrefix(String),
+    #[error("missing `{0}`")]
+    MissingField(&'static str),
+    #[error("too many fields")]
+    TooManyFields,
+    #[error("`{0}` is out of range")]
+    Overflow(&'static str),
+    #[error("`{0}` has a sign or leading zeros")]
+    NonCanonical(&'static str),
+    #[error("invalid `{field}`")]
+    InvalidNumber {
+        field: &'static str,
+        source: ParseIntError,
+    },
+}
+
+fn parse_field<'a, T>(
+    fields: &mut impl Iterator<Item = &'a str>,
+    field: &'static str,
+) -> Result<T, ParseOutPointError>
+where
+    T: FromStr<Err = ParseIntError>,
+{
+    let value = fields
+        .next()
+        .ok_or(ParseOutPointError::MissingField(field))?;
+    // Only accept what `Display` writes, so that parsing is the exact inverse.
+    if value.starts_with('+') || (value.len() > 1 && value.starts_with('0')) {
+        return Err(ParseOutPointError::NonCanonical(field));
+    }
+    value
+        .parse()
+        .map_err(|source: ParseIntError| match source.kind() {
+            IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => {
+                ParseOutPointError::Overflow(field)
+            }
+            _ => ParseOutPointError::InvalidNumber { field, source },
+        })
+}
+
+impl FromStr for OutPoint {
+    type Err = ParseOutPointError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split(':');
+        let prefix = fields.next().unwrap_or_default();
+        let outpoint = match prefix {
+            "r" => Self::Regular {
+                transaction_number: parse_field(&mut fields, "transaction_number")?,
+                output_number: parse_field(&mut fields, "output_number")?,
+            },
+            "c" => Self::Coinbase {
+                block_number: parse_field(&mut fields, "block_number")?,
+                output_number: parse_field(&mut fields, "output_number")?,
+            },
+            "d" => Self::Deposit {
+                sequence_number: parse_field(&mut fields, "sequence_number")?,
+            },
+            _ => return Err(ParseOutPointError::BadPrefix(prefix.to_owned())),
+        };
+        if fields.next().is_some() {
+            return Err(ParseOutPointError::TooManyFields);
+        }
+        Ok(outpoint)
+    }
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Output {
     Regular {
-        address: [u8; ADDRESS_LENGTH],
+        address: Address,
         value: u64,
     },
     Withdrawal {
-        address: [u8; ADDRESS_LENGTH],
-        // Must be P2PKH.
-        main_address: [u8; MAIN_ADDRESS_LENGTH],
+        address: Address,
+        main_address: WithdrawalDestination,
         value: u64,
         fee: u64,
     },
 }
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq, thiserror::Error)]
+pub enum AmountError {
+    #[error("amount overflowed")]
+    Overflow,
+    #[error("{0} sats is more than MAX_MONEY")]
+    AboveMaxMoney(u64),
+}
+
+fn check_money(value: u64) -> Result<u64, AmountError> {
+    if bitcoin::Amount::from_sat(value) > MAX_MONEY {
+        return Err(AmountError::AboveMaxMoney(value));
+    }
+    Ok(value)
+}
+
+fn checked_sum(
+    values: impl IntoIterator<Item = Result<u64, AmountError>>,
+) -> Result<u64, AmountError> {
+    values.into_iter().try_fold(0u64, |total, value| {
+        check_money(total.checked_add(value?).ok_or(AmountError::Overflow)?)
+    })
+}
+
 impl Output {
+    // Saturates instead of overflowing, use `checked_total_value` for
+    // validation.
     pub fn total_value(&self) -> u64 {
         match self {
             Self::Regular { value, .. } => *value,
-            Self::Withdrawal { value, fee, .. } => *value + *fee,
+            Self::Withdrawal { value, fee, .. } => value.saturating_add(*fee),
         }
     }
 
-    pub fn address(&self) -> [u8; ADDRESS_LENGTH] {
+    pub fn checked_total_value(&self) -> Result<u64, AmountError> {
+        match self {
+            Self::Regular { value, .. } => check_money(*value),
+            Self::Withdrawal { value, fee, .. } => {
+                check_money(value.checked_add(*fee).ok_or(AmountError::Overflow)?)
+            }
+        }
+    }
+
+    pub fn address(&self) -> Address {
         match self {
             Self::Regular { address, .. } => *address,
             Self::Withdrawal { address, .. } => *address,
@@ -73,7 +212,6 @@ impl Display for Output {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Regular { address, value } => {
-                let address = bs58::encode(&address).with_check().into_string();
                 let value = bitcoin::Amount::from_sat(*value);
                 write!(f, "{address}: {value}")
             }
@@ -83,12 +221,59 @@ impl Display for Output {
                 value,
                 fee,
             } => {
-                todo!();
+                let value = bitcoin::Amount::from_sat(*value);
+                let fee = bitcoin::Amount::from_sat(*fee);
+                write!(f, "{address}: {value} -> {main_address} (fee: {fee})")
             }
         }
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum ParseOutputError {
+    #[error("expected `<address>: <value>`")]
+    MissingValue,
+    #[error("expected `(fee: <fee>)` after the main address")]
+    MissingFee,
+    #[error("invalid address")]
+    Address(#[from] ParseAddressError),
+    #[error("invalid amount")]
+    Amount(#[source] <bitcoin::Amount as FromStr>::Err),
+    #[error(transparent)]
+    MainAddress(#[from] DestinationError),
+}
+
+fn parse_amount(s: &str) -> Result<u64, ParseOutputError> {
+    let amount = bitcoin::Amount::from_str(s).map_err(ParseOutputError::Amount)?;
+    Ok(amount.to_sat())
+}
+
+impl FromStr for Output {
+    type Err = ParseOutputError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, rest) = s.split_once(": ").ok_or(ParseOutputError::MissingValue)?;
+        let address: Address = address.parse()?;
+        let Some((value, rest)) = rest.split_once(" -> ") else {
+            let value = parse_amount(rest)?;
+            return Ok(Self::Regular { address, value });
+        };
+        let value = parse_amount(value)?;
+        let (main_address, fee) = rest
+            .strip_suffix(')')
+            .and_then(|rest| rest.split_once(" (fee: "))
+            .ok_or(ParseOutputError::MissingFee)?;
+        let main_address: WithdrawalDestination = main_address.parse()?;
+        let fee = parse_amount(fee)?;
+        Ok(Self::Withdrawal {
+            address,
+            main_address,
+            value,
+            fee,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub inputs: Vec<OutPoint>,
@@ -96,51 +281,205 @@ pub struct Transaction {
 }
 
 impl Transaction {
+    /// Identifies the transaction by its inputs and outputs only, so that it
+    /// can't be changed by changing the authorizations.
+    pub fn txid(&self) -> Result<Txid> {
+        Hashable::hash(self).map(Txid)
+    }
+
+    /// Size of the transaction without authorizations, in the same encoding
+    /// as `Block::serialized_size`.
+    pub fn size(&self) -> Result<u64> {
+        Ok(bincode::serialized_size(self)?)
+    }
+
+    // Saturates instead of overflowing, use `checked_value_out` for
+    // validation.
     pub fn value_out(&self) -> u64 {
-        self.outputs.iter().map(|output| output.total_value()).sum()
+        self.outputs.iter().fold(0, |total, output| {
+            total.saturating_add(output.total_value())
+        })
+    }
+
+    pub fn checked_value_out(&self) -> Result<u64, AmountError> {
+        checked_sum(self.outputs.iter().map(Output::checked_total_value))
+    }
+
+    pub fn value_in(&self, utxos: &impl UtxoView) -> Result<u64, TransactionError> {
+        let spent_outputs = self
+            .inputs
+            .iter()
+            .map(|outpoint| {
+                utxos
+                    .get_utxo(outpoint)
+                    .ok_or_else(|| TransactionError::MissingInput(outpoint.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let value_in = checked_sum(spent_outputs.iter().map(Output::checked_total_value))?;
+        Ok(value_in)
+    }
+
+    pub fn fee(&self, utxos: &impl UtxoView) -> Result<u64, TransactionError> {
+        let value_in = self.value_in(utxos)?;
+        let value_out = self.checked_value_out()?;
+        value_in
+            .checked_sub(value_out)
+            .ok_or(TransactionError::Overspend {
+                value_in,
+                value_out,
+            })
+    }
+
+    /// Check that every input exists and is spent only once, and that the
+    /// outputs don't spend more than the inputs. Returns the fee.
+    pub fn validate(&self, utxos: &impl UtxoView) -> Result<u64, TransactionError> {
+        let mut inputs = HashSet::new();
+        if let Some(outpoint) = self
+            .inputs
+            .iter()
+            .find(|outpoint| !inputs.insert(*outpoint))
+        {
+            return Err(TransactionError::DuplicateInput(outpoint.clone()));
+        }
+        self.fee(utxos)
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, thiserror::Error)]
+pub enum TransactionError {
+    #[error("input {0} doesn't exist")]
+    MissingInput(OutPoint),
+    #[error("input {0} is spent more than once")]
+    DuplicateInput(OutPoint),
+    #[error("outputs spend {value_out} sats but inputs only have {value_in}")]
+    Overspend { value_in: u64, value_out: u64 },
+    #[error(transparent)]
+    Amount(#[from] AmountError),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Header {
-    pub prev_side_block_hash: [u8; HASH_LENGTH],
+    pub prev_side_block_hash: BlockHash,
+    // Commits to txids.
     pub merkle_root: [u8; HASH_LENGTH],
+    // Commits to wtxids, and so to authorizations.
+    pub witness_merkle_root: [u8; HASH_LENGTH],
 }
 
-// 6000 withdrawals
-//
 // Deposits
 // BMM
 // Transactions
 // Wihdrawals
 
 impl Header {
+    pub fn hash(&self) -> Result<BlockHash> {
+        Hashable::hash(self).map(BlockHash)
+    }
+
+    fn merkle_leaves(
+        coinbase: &[Output],
+        ids: impl Iterator<Item = Result<[u8; HASH_LENGTH]>>,
+    ) -> Result<Vec<[u8; HASH_LENGTH]>> {
+        std::iter::once(coinbase.hash()).chain(ids).collect()
+    }
+
+    // The merkle root over the coinbase and txids, which both plain and
+    // authorized transactions have.
+    fn txid_merkle_root(
+        coinbase: &[Output],
+        txids: impl Iterator<Item = Result<Txid>>,
+    ) -> Result<[u8; HASH_LENGTH]> {
+        let txids = txids.map(|txid| txid.map(Into::into));
+        Ok(merkle::root(&Self::merkle_leaves(coinbase, txids)?))
+    }
+
     pub fn compute_merkle_root(
         coinbase: &[Output],
         transactions: &[Transaction],
-    ) -> [u8; HASH_LENGTH] {
-        // TODO: Make this into proper merkle root, not just hash of concatenated hashes.
-        let merkle_root: [u8; HASH_LENGTH] = blake3::hash(
-            &[
-                vec![coinbase.hash()],
-                transactions
-                    .iter()
-                    .map(|transaction| transaction.hash())
-                    .collect::<Vec<_>>(),
-            ]
-            .concat()
-            .concat(),
+    ) -> Result<[u8; HASH_LENGTH]> {
+        Self::txid_merkle_root(coinbase, transactions.iter().map(Transaction::txid))
+    }
+
+    pub fn compute_witness_merkle_root(
+        coinbase: &[Output],
+        transactions: &[AuthorizedTransaction],
+    ) -> Result<[u8; HASH_LENGTH]> {
+        let wtxids = transactions
+            .iter()
+            .map(|transaction| transaction.wtxid().map(Into::into));
+        Ok(merkle::root(&Self::merkle_leaves(coinbase, wtxids)?))
+    }
+
+    /// Prove that `transactions[index]` is included under the merkle root of
+    /// this block. The coinbase is always the first leaf, so transaction
+    /// `index` is leaf `index + 1`.
+    pub fn prove_transaction(
+        coinbase: &[Output],
+        transactions: &[Transaction],
+        index: usize,
+    ) -> Result<Option<merkle::MerkleProof>> {
+        let txids = transactions
+            .iter()
+            .map(|transaction| transaction.txid().map(Into::into));
+        let leaves = Self::merkle_leaves(coinbase, txids)?;
+        Ok(index
+            .checked_add(1)
+            .and_then(|index| merkle::prove(&leaves, index)))
+    }
+
+    /// Check a proof from `prove_transaction` against this header. Leaf 0 is
+    /// the coinbase, so a proof for it is rejected.
+    pub fn verify_transaction(&self, txid: &Txid, proof: &merkle::MerkleProof) -> bool {
+        proof.leaf_index != 0 && merkle::verify(self.merkle_root, txid.0, proof)
+    }
+
+    // Same as `compute_merkle_root`, without having to strip authorizations.
+    fn compute_authorized_merkle_root(
+        coinbase: &[Output],
+        transactions: &[AuthorizedTransaction],
+    ) -> Result<[u8; HASH_LENGTH]> {
+        Self::txid_merkle_root(
+            coinbase,
+            transactions.iter().map(AuthorizedTransaction::txid),
         )
-        .into();
-        merkle_root
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Block {
+    pub header: Header,
+    pub coinbase: Vec<Output>,
+    pub transactions: Vec<AuthorizedTransaction>,
+}
+
+impl Block {
+    /// Build a block, committing to `coinbase` and `transactions` in the header.
+    pub fn new(
+        prev_side_block_hash: BlockHash,
+        coinbase: Vec<Output>,
+        transactions: Vec<AuthorizedTransaction>,
+    ) -> Result<Self> {
+        let header = Header {
+            prev_side_block_hash,
+            merkle_root: Header::compute_authorized_merkle_root(&coinbase, &transactions)?,
+            witness_merkle_root: Header::compute_witness_merkle_root(&coinbase, &transactions)?,
+        };
+        Ok(Self {
+            header,
+            coinbase,
+            transactions,
+        })
     }
 
-    fn validate_block(&self, coinbase: &[Output], transactions: &[Transaction]) -> bool {
-        let merkle_root = Self::compute_merkle_root(coinbase, transactions);
-        self.merkle_root == merkle_root
+    /// Size of the block as encoded by `bincode::serialize` with its default
+    /// options: fixed width little endian integers, and a u64 length before
+    /// every `Vec`. This is what `BLOCK_SIZE_LIMIT` is checked against.
+    pub fn serialized_size(&self) -> Result<u64> {
+        Ok(bincode::serialized_size(self)?)
     }
 }
 
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct MainBlock {
     pub block_height: u32,
     pub block_hash: [u8; HASH_LENGTH],
@@ -149,25 +488,172 @@ pub struct MainBlock {
     pub bmm_hashes: Vec<[u8; HASH_LENGTH]>,
 }
 
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct WithdrawalBundleEvent {
     pub withdrawal_bundle_event_type: WithdrawalBundleEventType,
     pub m6id: [u8; HASH_LENGTH],
 }
 
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub enum WithdrawalBundleEventType {
     Submitted,
     Succeded,
     Failed,
 }
 
+impl MainBlock {
+    /// Encode for storage, with bincode's default options: integers are
+    /// fixed width little endian, and fields and variants are in declaration
+    /// order. The encoding only changes if these types do.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
 pub trait Hashable
 where
     Self: Serialize,
 {
-    fn hash(&self) -> [u8; HASH_LENGTH] {
-        let bytes = bincode::serialize(self).unwrap();
-        blake3::hash(&bytes).into()
+    fn hash(&self) -> Result<[u8; HASH_LENGTH]> {
+        let mut hasher = blake3::Hasher::new();
+        bincode::serialize_into(&mut hasher, self)?;
+        Ok(hasher.finalize().into())
     }
 }
 
 impl<T: Serialize> Hashable for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outpoint_round_trip() {
+        let outpoints = [
+            OutPoint::Regular {
+                transaction_number: u64::MAX,
+                output_number: 0,
+            },
+            OutPoint::Coinbase {
+                block_number: 7,
+                output_number: u8::MAX,
+            },
+            OutPoint::Deposit { sequence_number: 0 },
+        ];
+        for outpoint in outpoints {
+            assert_eq!(outpoint.to_string().parse::<OutPoint>().unwrap(), outpoint);
+        }
+    }
+
+    #[test]
+    fn output_round_trip() {
+        let address = Address::from([1; ADDRESS_LENGTH]);
+        let destinations = [
+            WithdrawalDestination::P2pkh(MainAddress([2; MAIN_ADDRESS_LENGTH])),
+            WithdrawalDestination::P2wpkh([3; 20]),
+            WithdrawalDestination::P2wsh([4; 32]),
+            WithdrawalDestination::P2tr([5; 32]),
+            WithdrawalDestination::Script(bitcoin::ScriptBuf::from_bytes(vec![0x51, 0x52])),
+        ];
+        let mut outputs = vec![
+            Output::Regular { address, value: 0 },
+            Output::Regular {
+                address,
+                value: MAX_MONEY.to_sat(),
+            },
+        ];
+        outputs.extend(
+            destinations
+                .into_iter()
+                .map(|main_address| Output::Withdrawal {
+                    address,
+                    main_address,
+                    value: 123_456_789,
+                    fee: 1,
+                }),
+        );
+        for output in outputs {
+            assert_eq!(output.to_string().parse::<Output>().unwrap(), output);
+        }
+    }
+
+    #[test]
+    fn invalid_outpoints() {
+        let parse = |s: &str| s.parse::<OutPoint>().unwrap_err();
+        assert!(matches!(parse("x:1:2"), ParseOutPointError::BadPrefix(prefix) if prefix == "x"));
+        assert!(matches!(parse(""), ParseOutPointError::BadPrefix(_)));
+        assert!(matches!(
+            parse("r:1"),
+            ParseOutPointError::MissingField("output_number")
+        ));
+        assert!(matches!(
+            parse("d"),
+            ParseOutPointError::MissingField("sequence_number")
+        ));
+        assert!(matches!(
+            parse("c:1:2:3"),
+            ParseOutPointError::TooManyFields
+        ));
+        assert!(matches!(
+            parse("r:1:256"),
+            ParseOutPointError::Overflow("output_number")
+        ));
+        assert!(matches!(
+            parse("c:4294967296:0"),
+            ParseOutPointError::Overflow("block_number")
+        ));
+        assert!(matches!(
+            parse("r::2"),
+            ParseOutPointError::InvalidNumber {
+                field: "transaction_number",
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse("r:-1:2"),
+            ParseOutPointError::InvalidNumber { .. }
+        ));
+        assert!(matches!(
+            parse("r:+1:2"),
+            ParseOutPointError::NonCanonical("transaction_number")
+        ));
+        assert!(matches!(
+            parse("d:007"),
+            ParseOutPointError::NonCanonical("sequence_number")
+        ));
+    }
+
+    #[test]
+    fn transaction_proofs_exclude_the_coinbase() {
+        let address = Address::from([1; ADDRESS_LENGTH]);
+        let coinbase = vec![Output::Regular { address, value: 1 }];
+        let transactions: Vec<Transaction> = (0..3)
+            .map(|value| Transaction {
+                inputs: vec![],
+                outputs: vec![Output::Regular { address, value }],
+            })
+            .collect();
+        let header = Header {
+            prev_side_block_hash: BlockHash::default(),
+            merkle_root: Header::compute_merkle_root(&coinbase, &transactions).unwrap(),
+            witness_merkle_root: [0; HASH_LENGTH],
+        };
+        for (index, transaction) in transactions.iter().enumerate() {
+            let proof = Header::prove_transaction(&coinbase, &transactions, index)
+                .unwrap()
+                .unwrap();
+            assert!(header.verify_transaction(&transaction.txid().unwrap(), &proof));
+        }
+
+        let txids = transactions.iter().map(Transaction::txid);
+        let leaves = Header::merkle_leaves(&coinbase, txids.map(|txid| txid.map(Into::into)));
+        let proof = merkle::prove(&leaves.unwrap(), 0).unwrap();
+        let coinbase_hash = Txid(coinbase.hash().unwrap());
+        assert!(merkle::verify(header.merkle_root, coinbase_hash.0, &proof));
+        assert!(!header.verify_transaction(&coinbase_hash, &proof));
+    }
+}
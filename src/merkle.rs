@@ -0,0 +1,186 @@
+use crate::HASH_LENGTH;
+use serde::{Deserialize, Serialize};
+
+// Leaves and interior nodes are hashed with distinct prefixes, so that an
+// interior node can never be passed off as a leaf (second preimage).
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+const ROOT_PREFIX: u8 = 0x02;
+
+pub fn hash_leaf(leaf: &[u8; HASH_LENGTH]) -> [u8; HASH_LENGTH] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[LEAF_PREFIX]);
+    hasher.update(leaf);
+    hasher.finalize().into()
+}
+
+pub fn hash_node(left: &[u8; HASH_LENGTH], right: &[u8; HASH_LENGTH]) -> [u8; HASH_LENGTH] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// The root commits to the number of leaves, so that a proof can't claim a
+// different position in a different sized tree with the same hashes.
+fn hash_root(leaf_count: u64, tree_root: &[u8; HASH_LENGTH]) -> [u8; HASH_LENGTH] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[ROOT_PREFIX]);
+    hasher.update(&leaf_count.to_le_bytes());
+    hasher.update(tree_root);
+    hasher.finalize().into()
+}
+
+// If a level has an odd number of nodes, the last one is carried up to the
+// next level unchanged instead of being paired with a copy of itself, so two
+// different leaf lists can never produce the same root.
+fn next_level(level: &[[u8; HASH_LENGTH]]) -> Vec<[u8; HASH_LENGTH]> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Compute the root of a binary merkle tree over `leaves`, which commits to
+/// the number of leaves as well as the tree.
+///
+/// The root of an empty tree is all zeroes.
+pub fn root(leaves: &[[u8; HASH_LENGTH]]) -> [u8; HASH_LENGTH] {
+    let mut level: Vec<[u8; HASH_LENGTH]> = leaves.iter().map(hash_leaf).collect();
+    if level.is_empty() {
+        return [0; HASH_LENGTH];
+    }
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    hash_root(leaves.len() as u64, &level[0])
+}
+
+/// Proof that a leaf is included in a merkle tree, listing the sibling hashes
+/// from the bottom of the tree up. Levels where the node was carried up
+/// without a sibling contribute no hash.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub leaf_index: u32,
+    pub leaf_count: u32,
+    pub siblings: Vec<[u8; HASH_LENGTH]>,
+}
+
+/// Build a proof for the leaf at `index`, or `None` if it is out of range.
+pub fn prove(leaves: &[[u8; HASH_LENGTH]], index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let leaf_index = u32::try_from(index).ok()?;
+    let leaf_count = u32::try_from(leaves.len()).ok()?;
+    let mut level: Vec<[u8; HASH_LENGTH]> = leaves.iter().map(hash_leaf).collect();
+    let mut position = index;
+    let mut siblings = vec![];
+    while level.len() > 1 {
+        if let Some(sibling) = level.get(position ^ 1) {
+            siblings.push(*sibling);
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+    Some(MerkleProof {
+        leaf_index,
+        leaf_count,
+        siblings,
+    })
+}
+
+/// Check that `leaf` is included under `root` according to `proof`.
+pub fn verify(root: [u8; HASH_LENGTH], leaf: [u8; HASH_LENGTH], proof: &MerkleProof) -> bool {
+    if proof.leaf_index >= proof.leaf_count {
+        return false;
+    }
+    let mut hash = hash_leaf(&leaf);
+    let mut position = proof.leaf_index;
+    let mut count = proof.leaf_count;
+    let mut siblings = proof.siblings.iter();
+    while count > 1 {
+        if position % 2 == 1 {
+            let Some(sibling) = siblings.next() else {
+                return false;
+            };
+            hash = hash_node(sibling, &hash);
+        } else if position + 1 < count {
+            let Some(sibling) = siblings.next() else {
+                return false;
+            };
+            hash = hash_node(&hash, sibling);
+        }
+        position /= 2;
+        count = count.div_ceil(2);
+    }
+    siblings.next().is_none() && hash_root(proof.leaf_count.into(), &hash) == root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<[u8; HASH_LENGTH]> {
+        (0..count).map(|i| [i as u8; HASH_LENGTH]).collect()
+    }
+
+    #[test]
+    fn proofs_round_trip() {
+        for count in 1..=40 {
+            let leaves = leaves(count);
+            let root = root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = prove(&leaves, index).unwrap();
+                assert!(verify(root, *leaf, &proof), "leaf {index} of {count}");
+            }
+            assert_eq!(prove(&leaves, count), None);
+        }
+    }
+
+    #[test]
+    fn tampered_proofs_are_rejected() {
+        for count in 1..=20 {
+            let leaves = leaves(count);
+            let root = root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = prove(&leaves, index).unwrap();
+                for leaf_index in 0..=count as u32 {
+                    for leaf_count in 0..=count as u32 + 1 {
+                        if (leaf_index, leaf_count) == (proof.leaf_index, proof.leaf_count) {
+                            continue;
+                        }
+                        let tampered = MerkleProof {
+                            leaf_index,
+                            leaf_count,
+                            ..proof.clone()
+                        };
+                        assert!(!verify(root, *leaf, &tampered));
+                    }
+                }
+                for sibling in 0..proof.siblings.len() {
+                    let mut tampered = proof.clone();
+                    tampered.siblings[sibling][0] ^= 1;
+                    assert!(!verify(root, *leaf, &tampered));
+                }
+                let mut other_leaf = *leaf;
+                other_leaf[0] ^= 0xff;
+                assert!(!verify(root, other_leaf, &proof));
+            }
+        }
+    }
+
+    #[test]
+    fn position_is_committed() {
+        let leaves = leaves(3);
+        let mut proof = prove(&leaves, 2).unwrap();
+        proof.leaf_index = 1;
+        proof.leaf_count = 2;
+        assert!(!verify(root(&leaves), leaves[2], &proof));
+    }
+}
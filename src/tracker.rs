@@ -0,0 +1,254 @@
+use crate::{
+    BundleError, OutPoint, WithdrawalBundle, WithdrawalBundleEvent, WithdrawalBundleEventType,
+    HASH_LENGTH,
+};
+use bitcoin::hex::DisplayHex as _;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TrackerError {
+    #[error(transparent)]
+    Bundle(#[from] BundleError),
+    #[error("withdrawal {0} is already tracked")]
+    AlreadyTracked(OutPoint),
+    #[error("withdrawal {0} is not tracked")]
+    UnknownWithdrawal(OutPoint),
+    #[error("withdrawal {outpoint} is not pending")]
+    NotPending {
+        outpoint: OutPoint,
+        status: WithdrawalStatus,
+    },
+    #[error("bundle {} is already tracked", .0.as_hex())]
+    BundleExists([u8; HASH_LENGTH]),
+    #[error("bundle {} is not tracked", .0.as_hex())]
+    UnknownBundle([u8; HASH_LENGTH]),
+    #[error("bundle {} can't go from {from:?} to {to:?}", .m6id.as_hex())]
+    IllegalTransition {
+        m6id: [u8; HASH_LENGTH],
+        from: BundleStatus,
+        to: BundleStatus,
+    },
+}
+
+/// Where a withdrawal is on its way to the mainchain. All but `Pending`
+/// carry the M6ID of the bundle the withdrawal is in.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum WithdrawalStatus {
+    /// Waiting to be put in a bundle.
+    Pending,
+    InBundle([u8; HASH_LENGTH]),
+    /// Paid out on the mainchain.
+    Confirmed([u8; HASH_LENGTH]),
+    /// The bundle failed, and the withdrawal was refunded on the sidechain.
+    Refunded([u8; HASH_LENGTH]),
+}
+
+/// Where a bundle is on the mainchain.
+///
+/// `Proposed` bundles only exist on the sidechain so far. The others follow
+/// `WithdrawalBundleEventType`: `Proposed -> Submitted -> Succeeded` or
+/// `Submitted -> Failed`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum BundleStatus {
+    Proposed,
+    Submitted,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct TrackedBundle {
+    outpoints: Vec<OutPoint>,
+    status: BundleStatus,
+}
+
+/// Tracks withdrawals from the sidechain output that created them until
+/// they are paid out or refunded, following the bundles they are put in.
+///
+/// Nothing is changed by a call that returns an error.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct WithdrawalTracker {
+    withdrawals: HashMap<OutPoint, WithdrawalStatus>,
+    bundles: HashMap<[u8; HASH_LENGTH], TrackedBundle>,
+}
+
+impl WithdrawalTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn status(&self, outpoint: &OutPoint) -> Option<WithdrawalStatus> {
+        self.withdrawals.get(outpoint).copied()
+    }
+
+    pub fn bundle_status(&self, m6id: &[u8; HASH_LENGTH]) -> Option<BundleStatus> {
+        self.bundles.get(m6id).map(|bundle| bundle.status)
+    }
+
+    /// Outpoints of the withdrawals in a bundle, in bundle order.
+    pub fn bundle_outpoints(&self, m6id: &[u8; HASH_LENGTH]) -> Option<&[OutPoint]> {
+        self.bundles
+            .get(m6id)
+            .map(|bundle| bundle.outpoints.as_slice())
+    }
+
+    /// Withdrawals waiting to be put in a bundle.
+    pub fn pending(&self) -> impl Iterator<Item = &OutPoint> {
+        self.withdrawals
+            .iter()
+            .filter(|(_, status)| **status == WithdrawalStatus::Pending)
+            .map(|(outpoint, _)| outpoint)
+    }
+
+    /// Start tracking a new withdrawal as pending.
+    pub fn add_withdrawal(&mut self, outpoint: OutPoint) -> Result<(), TrackerError> {
+        if self.withdrawals.contains_key(&outpoint) {
+            return Err(TrackerError::AlreadyTracked(outpoint));
+        }
+        self.withdrawals.insert(outpoint, WithdrawalStatus::Pending);
+        Ok(())
+    }
+
+    /// Stop tracking a pending withdrawal, e.g. when the output that created
+    /// it is disconnected.
+    pub fn remove_withdrawal(&mut self, outpoint: &OutPoint) -> Result<(), TrackerError> {
+        self.check_pending(outpoint)?;
+        self.withdrawals.remove(outpoint);
+        Ok(())
+    }
+
+    /// Put pending withdrawals in a new bundle, returning its M6ID.
+    pub fn propose_bundle(
+        &mut self,
+        bundle: &WithdrawalBundle,
+    ) -> Result<[u8; HASH_LENGTH], TrackerError> {
+        let m6id = bundle.m6id()?;
+        if self.bundles.contains_key(&m6id) {
+            return Err(TrackerError::BundleExists(m6id));
+        }
+        for outpoint in bundle.outpoints() {
+            self.check_pending(outpoint)?;
+        }
+        let outpoints: Vec<OutPoint> = bundle.outpoints().cloned().collect();
+        for outpoint in &outpoints {
+            self.withdrawals
+                .insert(outpoint.clone(), WithdrawalStatus::InBundle(m6id));
+        }
+        self.bundles.insert(
+            m6id,
+            TrackedBundle {
+                outpoints,
+                status: BundleStatus::Proposed,
+            },
+        );
+        Ok(m6id)
+    }
+
+    /// Drop a bundle that was never submitted, returning its withdrawals to
+    /// pending.
+    pub fn discard_bundle(&mut self, m6id: &[u8; HASH_LENGTH]) -> Result<(), TrackerError> {
+        // Reported as a move back to `Proposed`, the only status a bundle
+        // can be discarded from.
+        self.check_status(m6id, BundleStatus::Proposed, BundleStatus::Proposed)?;
+        if let Some(bundle) = self.bundles.remove(m6id) {
+            for outpoint in bundle.outpoints {
+                self.withdrawals.insert(outpoint, WithdrawalStatus::Pending);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that `apply_event` would accept `event`, without changing
+    /// anything.
+    pub fn check_event(&self, event: &WithdrawalBundleEvent) -> Result<(), TrackerError> {
+        let (from, to) = transition(event.withdrawal_bundle_event_type);
+        self.check_status(&event.m6id, from, to)
+    }
+
+    /// Move a bundle, and the withdrawals in it, on according to a mainchain
+    /// event.
+    pub fn apply_event(&mut self, event: &WithdrawalBundleEvent) -> Result<(), TrackerError> {
+        self.check_event(event)?;
+        let (_, to) = transition(event.withdrawal_bundle_event_type);
+        let m6id = event.m6id;
+        let withdrawal_status = match to {
+            BundleStatus::Succeeded => WithdrawalStatus::Confirmed(m6id),
+            BundleStatus::Failed => WithdrawalStatus::Refunded(m6id),
+            BundleStatus::Proposed | BundleStatus::Submitted => WithdrawalStatus::InBundle(m6id),
+        };
+        self.set_status(&m6id, to, withdrawal_status);
+        Ok(())
+    }
+
+    /// Check that `undo_event` would accept `event`, without changing
+    /// anything.
+    pub fn check_undo_event(&self, event: &WithdrawalBundleEvent) -> Result<(), TrackerError> {
+        let (from, to) = transition(event.withdrawal_bundle_event_type);
+        self.check_status(&event.m6id, to, from)
+    }
+
+    /// Move a bundle back to where it was before `event`, when the mainchain
+    /// block with the event is disconnected.
+    pub fn undo_event(&mut self, event: &WithdrawalBundleEvent) -> Result<(), TrackerError> {
+        self.check_undo_event(event)?;
+        let (from, _) = transition(event.withdrawal_bundle_event_type);
+        let m6id = event.m6id;
+        self.set_status(&m6id, from, WithdrawalStatus::InBundle(m6id));
+        Ok(())
+    }
+
+    fn check_status(
+        &self,
+        m6id: &[u8; HASH_LENGTH],
+        from: BundleStatus,
+        to: BundleStatus,
+    ) -> Result<(), TrackerError> {
+        let bundle = self
+            .bundles
+            .get(m6id)
+            .ok_or(TrackerError::UnknownBundle(*m6id))?;
+        if bundle.status != from {
+            return Err(TrackerError::IllegalTransition {
+                m6id: *m6id,
+                from: bundle.status,
+                to,
+            });
+        }
+        Ok(())
+    }
+
+    fn set_status(
+        &mut self,
+        m6id: &[u8; HASH_LENGTH],
+        status: BundleStatus,
+        withdrawal_status: WithdrawalStatus,
+    ) {
+        if let Some(bundle) = self.bundles.get_mut(m6id) {
+            bundle.status = status;
+            for outpoint in &bundle.outpoints {
+                self.withdrawals.insert(outpoint.clone(), withdrawal_status);
+            }
+        }
+    }
+
+    fn check_pending(&self, outpoint: &OutPoint) -> Result<(), TrackerError> {
+        match self.withdrawals.get(outpoint) {
+            None => Err(TrackerError::UnknownWithdrawal(outpoint.clone())),
+            Some(WithdrawalStatus::Pending) => Ok(()),
+            Some(status) => Err(TrackerError::NotPending {
+                outpoint: outpoint.clone(),
+                status: *status,
+            }),
+        }
+    }
+}
+
+// The bundle status an event moves from, and the one it moves to.
+fn transition(event_type: WithdrawalBundleEventType) -> (BundleStatus, BundleStatus) {
+    match event_type {
+        WithdrawalBundleEventType::Submitted => (BundleStatus::Proposed, BundleStatus::Submitted),
+        WithdrawalBundleEventType::Succeded => (BundleStatus::Submitted, BundleStatus::Succeeded),
+        WithdrawalBundleEventType::Failed => (BundleStatus::Submitted, BundleStatus::Failed),
+    }
+}
@@ -0,0 +1,608 @@
+use crate::{
+    AmountError, Block, ConsensusParams, MainBlock, OutPoint, Output, Result, TrackerError,
+    WithdrawalBundle, WithdrawalBundleEvent, WithdrawalBundleEventType, WithdrawalStatus,
+    WithdrawalTracker, HASH_LENGTH,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, thiserror::Error)]
+pub enum UtxoError {
+    #[error("too many blocks or transactions to number")]
+    CountOverflow,
+    #[error("{0} is not a deposit outpoint")]
+    NotADeposit(OutPoint),
+    #[error("deposit {0} is not a regular output")]
+    DepositNotRegular(OutPoint),
+    #[error("expected deposit {expected}, got {actual}")]
+    DepositOutOfOrder { expected: u64, actual: u64 },
+    #[error("deposit {outpoint} has an invalid value")]
+    DepositValue {
+        outpoint: OutPoint,
+        source: AmountError,
+    },
+    #[error("undo data is for block {0}, which is not the last block applied")]
+    UndoNotTip(u32),
+    #[error("undo data is not for the last mainchain block applied")]
+    MainUndoNotTip,
+    #[error("undo data doesn't match the state at {0}")]
+    UndoMismatch(OutPoint),
+    #[error("withdrawal {0} is not an unspent withdrawal output")]
+    NotAnUnspentWithdrawal(OutPoint),
+}
+
+/// Read access to unspent outputs, for checks that don't need to know how
+/// they are stored.
+pub trait UtxoView {
+    fn get_utxo(&self, outpoint: &OutPoint) -> Option<Output>;
+}
+
+impl UtxoView for HashMap<OutPoint, Output> {
+    fn get_utxo(&self, outpoint: &OutPoint) -> Option<Output> {
+        self.get(outpoint).cloned()
+    }
+}
+
+/// What applying a block changed, so that it can be disconnected again.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BlockUndo {
+    pub block_number: u32,
+    pub first_transaction_number: u64,
+    // Outputs spent by the block, in the order they were spent.
+    pub spent: Vec<(OutPoint, Output)>,
+    pub created: Vec<OutPoint>,
+}
+
+/// What applying a mainchain block changed, so that it can be disconnected
+/// again.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MainBlockUndo {
+    pub prev_last_deposit: Option<u64>,
+    pub deposits: Vec<OutPoint>,
+    // The block's bundle event, if it was for a tracked bundle.
+    pub withdrawal_bundle_event: Option<WithdrawalBundleEvent>,
+    // Withdrawals paid out by a bundle that succeeded, as they were before.
+    pub paid_out: Vec<(OutPoint, Output)>,
+    // Withdrawals refunded by a bundle that failed, as they were before.
+    pub refunded: Vec<(OutPoint, Output)>,
+}
+
+fn refund(withdrawal: &Output) -> Output {
+    Output::Regular {
+        address: withdrawal.address(),
+        value: withdrawal.total_value(),
+    }
+}
+
+/// Unspent outputs, along with the counters that number new outpoints.
+///
+/// Coinbase outputs of the n-th block applied get `OutPoint::Coinbase` with
+/// `block_number` n, and outputs of the n-th transaction get
+/// `OutPoint::Regular` with `transaction_number` n, both counting from zero.
+///
+/// Withdrawal outputs and the bundles they are in are tracked by a
+/// `WithdrawalTracker`, moved on by the bundle events of mainchain blocks.
+/// When a bundle succeeds its withdrawals are removed, as they have been paid
+/// out. When it fails each of them is refunded in place: the output at the
+/// same outpoint becomes `Output::Regular` to the withdrawal's `address`,
+/// worth its `value` plus `fee`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct UtxoSet {
+    utxos: HashMap<OutPoint, Output>,
+    block_count: u32,
+    transaction_count: u64,
+    // Sequence number of the last deposit added.
+    last_deposit: Option<u64>,
+    withdrawals: WithdrawalTracker,
+}
+
+impl UtxoView for UtxoSet {
+    fn get_utxo(&self, outpoint: &OutPoint) -> Option<Output> {
+        self.get(outpoint).cloned()
+    }
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&Output> {
+        self.utxos.get(outpoint)
+    }
+
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.utxos.contains_key(outpoint)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&OutPoint, &Output)> {
+        self.utxos.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.utxos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.utxos.is_empty()
+    }
+
+    pub fn block_count(&self) -> u32 {
+        self.block_count
+    }
+
+    pub fn transaction_count(&self) -> u64 {
+        self.transaction_count
+    }
+
+    pub fn last_deposit(&self) -> Option<u64> {
+        self.last_deposit
+    }
+
+    pub fn withdrawals(&self) -> &WithdrawalTracker {
+        &self.withdrawals
+    }
+
+    /// Register a bundle, so that it can be paid out or refunded. Every
+    /// withdrawal in it must be unspent, unchanged, and not in another
+    /// bundle.
+    pub fn add_withdrawal_bundle(
+        &mut self,
+        bundle: &WithdrawalBundle,
+    ) -> Result<[u8; HASH_LENGTH]> {
+        if let Some((outpoint, _)) = bundle
+            .withdrawals()
+            .iter()
+            .find(|(outpoint, output)| self.utxos.get(outpoint) != Some(output))
+        {
+            return Err(UtxoError::NotAnUnspentWithdrawal(outpoint.clone()).into());
+        }
+        Ok(self.withdrawals.propose_bundle(bundle)?)
+    }
+
+    /// Forget a registered bundle that hasn't been submitted to the
+    /// mainchain, so that its withdrawals can be bundled again.
+    pub fn remove_withdrawal_bundle(&mut self, m6id: &[u8; HASH_LENGTH]) -> Result<()> {
+        Ok(self.withdrawals.discard_bundle(m6id)?)
+    }
+
+    /// Spend the inputs and add the outputs of a block, after checking it
+    /// with `Block::validate_contents`. Nothing is changed if the block is
+    /// invalid.
+    pub fn apply_block(&mut self, block: &Block, params: &ConsensusParams) -> Result<BlockUndo> {
+        block.validate_contents(self, params)?;
+        let block_number = self.block_count;
+        let next_block_count = block_number
+            .checked_add(1)
+            .ok_or(UtxoError::CountOverflow)?;
+        let next_transaction_count = u64::try_from(block.transactions.len())
+            .ok()
+            .and_then(|count| self.transaction_count.checked_add(count))
+            .ok_or(UtxoError::CountOverflow)?;
+        let coinbase_outputs = (0..=u8::MAX)
+            .zip(&block.coinbase)
+            .map(|(output_number, output)| {
+                let outpoint = OutPoint::Coinbase {
+                    block_number,
+                    output_number,
+                };
+                (outpoint, output)
+            });
+        let transaction_outputs = (self.transaction_count..)
+            .zip(&block.transactions)
+            .flat_map(|(transaction_number, transaction)| {
+                (0..=u8::MAX).zip(&transaction.transaction.outputs).map(
+                    move |(output_number, output)| {
+                        let outpoint = OutPoint::Regular {
+                            transaction_number,
+                            output_number,
+                        };
+                        (outpoint, output)
+                    },
+                )
+            });
+        let created: Vec<(OutPoint, &Output)> =
+            coinbase_outputs.chain(transaction_outputs).collect();
+        if let Some((outpoint, _)) = created
+            .iter()
+            .find(|(outpoint, _)| self.withdrawals.status(outpoint).is_some())
+        {
+            return Err(TrackerError::AlreadyTracked(outpoint.clone()).into());
+        }
+
+        let mut undo = BlockUndo {
+            block_number,
+            first_transaction_number: self.transaction_count,
+            spent: vec![],
+            created: vec![],
+        };
+        for transaction in &block.transactions {
+            for outpoint in &transaction.transaction.inputs {
+                if let Some(output) = self.utxos.remove(outpoint) {
+                    undo.spent.push((outpoint.clone(), output));
+                }
+            }
+        }
+        for (outpoint, output) in created {
+            if let Output::Withdrawal { .. } = output {
+                self.withdrawals.add_withdrawal(outpoint.clone())?;
+            }
+            self.utxos.insert(outpoint.clone(), output.clone());
+            undo.created.push(outpoint);
+        }
+        self.block_count = next_block_count;
+        self.transaction_count = next_transaction_count;
+        Ok(undo)
+    }
+
+    /// Undo the last block applied, restoring the exact state from before
+    /// it. Withdrawals it created must not be in a bundle, so their bundles
+    /// have to be removed first. Nothing is changed if `undo` doesn't match
+    /// that block.
+    pub fn disconnect_block(&mut self, undo: &BlockUndo) -> Result<()> {
+        if undo.block_number.checked_add(1) != Some(self.block_count) {
+            return Err(UtxoError::UndoNotTip(undo.block_number).into());
+        }
+        if let Some(outpoint) = undo.created.iter().find(|outpoint| {
+            !self.utxos.contains_key(outpoint)
+                || self
+                    .withdrawals
+                    .status(outpoint)
+                    .is_some_and(|status| status != WithdrawalStatus::Pending)
+        }) {
+            return Err(UtxoError::UndoMismatch(outpoint.clone()).into());
+        }
+        if let Some((outpoint, _)) = undo
+            .spent
+            .iter()
+            .find(|(outpoint, _)| self.utxos.contains_key(outpoint))
+        {
+            return Err(UtxoError::UndoMismatch(outpoint.clone()).into());
+        }
+        for outpoint in &undo.created {
+            if self.withdrawals.status(outpoint).is_some() {
+                self.withdrawals.remove_withdrawal(outpoint)?;
+            }
+            self.utxos.remove(outpoint);
+        }
+        self.utxos.extend(undo.spent.iter().cloned());
+        self.block_count = undo.block_number;
+        self.transaction_count = undo.first_transaction_number;
+        Ok(())
+    }
+
+    /// Add the deposits of a mainchain block, and move on the bundle its
+    /// event is for. Deposit sequence numbers must follow on from the last
+    /// deposit added with no gaps, starting from 0, and deposits must be
+    /// regular outputs. Events for bundles that aren't tracked, such as ones
+    /// proposed elsewhere, are ignored; a tracked bundle must be in the state
+    /// the event moves it from. Nothing is changed if anything is invalid.
+    pub fn apply_main_block(&mut self, main_block: &MainBlock) -> Result<MainBlockUndo> {
+        let last_deposit = self.check_deposits(&main_block.deposits)?;
+        let event = main_block
+            .withdrawal_bundle_event
+            .filter(|event| self.withdrawals.bundle_status(&event.m6id).is_some());
+        let mut undo = MainBlockUndo {
+            prev_last_deposit: self.last_deposit,
+            deposits: main_block
+                .deposits
+                .iter()
+                .map(|(outpoint, _)| outpoint.clone())
+                .collect(),
+            withdrawal_bundle_event: event,
+            paid_out: vec![],
+            refunded: vec![],
+        };
+        if let Some(event) = &event {
+            self.withdrawals.check_event(event)?;
+            match event.withdrawal_bundle_event_type {
+                WithdrawalBundleEventType::Submitted => {}
+                WithdrawalBundleEventType::Succeded => {
+                    undo.paid_out = self.bundle_withdrawals(&event.m6id)?;
+                }
+                WithdrawalBundleEventType::Failed => {
+                    undo.refunded = self.bundle_withdrawals(&event.m6id)?;
+                }
+            }
+            self.withdrawals.apply_event(event)?;
+        }
+
+        self.utxos.extend(main_block.deposits.iter().cloned());
+        for (outpoint, _) in &undo.paid_out {
+            self.utxos.remove(outpoint);
+        }
+        for (outpoint, output) in &undo.refunded {
+            self.utxos.insert(outpoint.clone(), refund(output));
+        }
+        self.last_deposit = last_deposit;
+        Ok(undo)
+    }
+
+    // Check that `deposits` follow on from the last deposit, returning the
+    // new last deposit.
+    fn check_deposits(&self, deposits: &[(OutPoint, Output)]) -> Result<Option<u64>, UtxoError> {
+        let mut last_deposit = self.last_deposit;
+        for (outpoint, output) in deposits {
+            let OutPoint::Deposit { sequence_number } = *outpoint else {
+                return Err(UtxoError::NotADeposit(outpoint.clone()));
+            };
+            let expected = match last_deposit {
+                Some(last) => last.checked_add(1).ok_or(UtxoError::CountOverflow)?,
+                None => 0,
+            };
+            if sequence_number != expected {
+                return Err(UtxoError::DepositOutOfOrder {
+                    expected,
+                    actual: sequence_number,
+                });
+            }
+            let Output::Regular { .. } = output else {
+                return Err(UtxoError::DepositNotRegular(outpoint.clone()));
+            };
+            output
+                .checked_total_value()
+                .map_err(|source| UtxoError::DepositValue {
+                    outpoint: outpoint.clone(),
+                    source,
+                })?;
+            last_deposit = Some(sequence_number);
+        }
+        Ok(last_deposit)
+    }
+
+    // The withdrawal outputs of a registered bundle, checking that they are
+    // all still unspent withdrawals.
+    fn bundle_withdrawals(
+        &self,
+        m6id: &[u8; HASH_LENGTH],
+    ) -> Result<Vec<(OutPoint, Output)>, UtxoError> {
+        let outpoints = self.withdrawals.bundle_outpoints(m6id).unwrap_or_default();
+        outpoints
+            .iter()
+            .map(|outpoint| match self.utxos.get(outpoint) {
+                Some(output @ Output::Withdrawal { .. }) => {
+                    output
+                        .checked_total_value()
+                        .map_err(|_| UtxoError::NotAnUnspentWithdrawal(outpoint.clone()))?;
+                    Ok((outpoint.clone(), output.clone()))
+                }
+                _ => Err(UtxoError::NotAnUnspentWithdrawal(outpoint.clone())),
+            })
+            .collect()
+    }
+
+    /// Undo the last mainchain block applied. Its deposits and refunds must
+    /// not have been spent, so sidechain blocks spending them have to be
+    /// disconnected first. Nothing is changed if `undo` doesn't match the
+    /// state.
+    pub fn disconnect_main_block(&mut self, undo: &MainBlockUndo) -> Result<()> {
+        let last_deposit = match undo.deposits.last() {
+            Some(OutPoint::Deposit { sequence_number }) => Some(*sequence_number),
+            Some(outpoint) => return Err(UtxoError::NotADeposit(outpoint.clone()).into()),
+            None => undo.prev_last_deposit,
+        };
+        if last_deposit != self.last_deposit {
+            return Err(UtxoError::MainUndoNotTip.into());
+        }
+        if let Some(outpoint) = undo
+            .deposits
+            .iter()
+            .find(|outpoint| !self.utxos.contains_key(outpoint))
+        {
+            return Err(UtxoError::UndoMismatch(outpoint.clone()).into());
+        }
+        if let Some((outpoint, _)) = undo
+            .paid_out
+            .iter()
+            .find(|(outpoint, _)| self.utxos.contains_key(outpoint))
+        {
+            return Err(UtxoError::UndoMismatch(outpoint.clone()).into());
+        }
+        if let Some((outpoint, _)) = undo
+            .refunded
+            .iter()
+            .find(|(outpoint, output)| self.utxos.get(outpoint) != Some(&refund(output)))
+        {
+            return Err(UtxoError::UndoMismatch(outpoint.clone()).into());
+        }
+        if let Some(event) = &undo.withdrawal_bundle_event {
+            self.withdrawals.undo_event(event)?;
+        }
+        for outpoint in &undo.deposits {
+            self.utxos.remove(outpoint);
+        }
+        self.utxos.extend(undo.paid_out.iter().cloned());
+        self.utxos.extend(undo.refunded.iter().cloned());
+        self.last_deposit = undo.prev_last_deposit;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Address, AuthorizedTransaction, BlockHash, Transaction, WithdrawalDestination,
+        ADDRESS_LENGTH,
+    };
+    use bitcoin::secp256k1::{Keypair, Secp256k1, SecretKey};
+
+    fn keypair() -> Keypair {
+        let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        Keypair::from_secret_key(&Secp256k1::new(), &secret_key)
+    }
+
+    fn main_block(
+        deposits: Vec<(OutPoint, Output)>,
+        withdrawal_bundle_event: Option<WithdrawalBundleEvent>,
+    ) -> MainBlock {
+        MainBlock {
+            block_height: 0,
+            block_hash: [0; HASH_LENGTH],
+            deposits,
+            withdrawal_bundle_event,
+            bmm_hashes: vec![],
+        }
+    }
+
+    // A deposit, then a block that spends it into a withdrawal and change.
+    fn deposit_and_withdraw(utxos: &mut UtxoSet) -> (MainBlockUndo, BlockUndo) {
+        let keypair = keypair();
+        let address = Address::from_public_key(&keypair.x_only_public_key().0);
+        let deposit = (
+            OutPoint::Deposit { sequence_number: 0 },
+            Output::Regular {
+                address,
+                value: 10_000,
+            },
+        );
+        let main_undo = utxos
+            .apply_main_block(&main_block(vec![deposit.clone()], None))
+            .unwrap();
+        let transaction = Transaction {
+            inputs: vec![deposit.0],
+            outputs: vec![
+                Output::Withdrawal {
+                    address,
+                    main_address: WithdrawalDestination::P2wpkh([2; 20]),
+                    value: 6_000,
+                    fee: 1_000,
+                },
+                Output::Regular {
+                    address,
+                    value: 3_000,
+                },
+            ],
+        };
+        let params = ConsensusParams::default();
+        let transaction = AuthorizedTransaction::sign(
+            transaction,
+            params.sidechain_number,
+            &[deposit.1],
+            &[keypair],
+        )
+        .unwrap();
+        let block = Block::new(BlockHash::default(), vec![], vec![transaction]).unwrap();
+        let undo = utxos.apply_block(&block, &params).unwrap();
+        (main_undo, undo)
+    }
+
+    #[test]
+    fn disconnect_restores_state() {
+        let mut utxos = UtxoSet::new();
+        let empty = utxos.clone();
+        let (main_undo, undo) = deposit_and_withdraw(&mut utxos);
+        assert_eq!(utxos.block_count(), 1);
+        assert_eq!(utxos.transaction_count(), 1);
+        assert_eq!(utxos.last_deposit(), Some(0));
+
+        utxos.disconnect_block(&undo).unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos.block_count(), 0);
+        utxos.disconnect_main_block(&main_undo).unwrap();
+        assert_eq!(utxos, empty);
+    }
+
+    #[test]
+    fn bundle_is_paid_out_or_refunded_once() {
+        let mut utxos = UtxoSet::new();
+        deposit_and_withdraw(&mut utxos);
+        let withdrawal = OutPoint::Regular {
+            transaction_number: 0,
+            output_number: 0,
+        };
+        let output = utxos.get(&withdrawal).unwrap().clone();
+        let bundle = WithdrawalBundle::new([(withdrawal.clone(), output.clone())]).unwrap();
+        let m6id = utxos.add_withdrawal_bundle(&bundle).unwrap();
+        assert!(utxos.add_withdrawal_bundle(&bundle).is_err());
+        let event = |withdrawal_bundle_event_type| {
+            Some(WithdrawalBundleEvent {
+                withdrawal_bundle_event_type,
+                m6id,
+            })
+        };
+        utxos
+            .apply_main_block(&main_block(
+                vec![],
+                event(WithdrawalBundleEventType::Submitted),
+            ))
+            .unwrap();
+        let submitted = utxos.clone();
+
+        let undo = utxos
+            .apply_main_block(&main_block(
+                vec![],
+                event(WithdrawalBundleEventType::Succeded),
+            ))
+            .unwrap();
+        assert!(!utxos.contains(&withdrawal));
+        assert!(utxos
+            .apply_main_block(&main_block(
+                vec![],
+                event(WithdrawalBundleEventType::Failed)
+            ))
+            .is_err());
+        utxos.disconnect_main_block(&undo).unwrap();
+        assert_eq!(utxos, submitted);
+
+        let undo = utxos
+            .apply_main_block(&main_block(
+                vec![],
+                event(WithdrawalBundleEventType::Failed),
+            ))
+            .unwrap();
+        assert_eq!(
+            utxos.get(&withdrawal),
+            Some(&Output::Regular {
+                address: output.address(),
+                value: 7_000,
+            })
+        );
+        assert!(utxos
+            .apply_main_block(&main_block(
+                vec![],
+                event(WithdrawalBundleEventType::Failed)
+            ))
+            .is_err());
+        utxos.disconnect_main_block(&undo).unwrap();
+        assert_eq!(utxos, submitted);
+    }
+
+    #[test]
+    fn events_for_unknown_bundles_are_ignored() {
+        let mut utxos = UtxoSet::new();
+        deposit_and_withdraw(&mut utxos);
+        let before = utxos.clone();
+        let deposit = (
+            OutPoint::Deposit { sequence_number: 1 },
+            Output::Regular {
+                address: Address::from([1; ADDRESS_LENGTH]),
+                value: 5_000,
+            },
+        );
+        let undo = utxos
+            .apply_main_block(&main_block(
+                vec![deposit.clone()],
+                Some(WithdrawalBundleEvent {
+                    withdrawal_bundle_event_type: WithdrawalBundleEventType::Succeded,
+                    m6id: [9; HASH_LENGTH],
+                }),
+            ))
+            .unwrap();
+        assert_eq!(undo.withdrawal_bundle_event, None);
+        assert_eq!(utxos.get(&deposit.0), Some(&deposit.1));
+        assert_eq!(utxos.last_deposit(), Some(1));
+        let withdrawal = OutPoint::Regular {
+            transaction_number: 0,
+            output_number: 0,
+        };
+        assert!(utxos.contains(&withdrawal));
+        assert_eq!(
+            utxos.withdrawals().status(&withdrawal),
+            Some(WithdrawalStatus::Pending)
+        );
+
+        utxos.disconnect_main_block(&undo).unwrap();
+        assert_eq!(utxos, before);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{
+    checked_sum, verify_authorizations, AmountError, AuthorizationError, Block, BlockHash, Error,
+    Header, OutPoint, Output, Result, TransactionError, UtxoView, BLOCK_SIZE_LIMIT,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ConsensusParams {
+    pub block_size_limit: u64,
+    // Value the coinbase may create on top of the fees of its block.
+    pub block_subsidy: u64,
+    // BIP300 slot of the sidechain, which every signature commits to.
+    pub sidechain_number: u8,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        Self {
+            block_size_limit: BLOCK_SIZE_LIMIT as u64,
+            block_subsidy: 0,
+            sidechain_number: 0,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BlockValidationError {
+    #[error("block builds on {actual}, not on the previous header {expected}")]
+    WrongPrevHash {
+        expected: BlockHash,
+        actual: BlockHash,
+    },
+    #[error("block is {size} bytes, more than {limit}")]
+    TooLarge { size: u64, limit: u64 },
+    #[error("merkle root doesn't match the transactions")]
+    WrongMerkleRoot,
+    #[error("witness merkle root doesn't match the transactions")]
+    WrongWitnessMerkleRoot,
+    #[error("coinbase has {0} outputs, more than an outpoint can number")]
+    TooManyCoinbaseOutputs(usize),
+    #[error("coinbase value is invalid")]
+    CoinbaseValue(#[source] AmountError),
+    #[error("coinbase creates {value} sats, more than subsidy and fees of {limit}")]
+    CoinbaseTooLarge { value: u64, limit: u64 },
+    #[error("fees of the block are invalid")]
+    Fees(#[source] AmountError),
+    #[error("transaction {index} has {outputs} outputs, more than an outpoint can number")]
+    TooManyOutputs { index: usize, outputs: usize },
+    #[error("transaction {index} spends {outpoint}, which doesn't exist")]
+    MissingInput { index: usize, outpoint: OutPoint },
+    #[error("transaction {index} spends {outpoint}, which is already spent in this block")]
+    DoubleSpend { index: usize, outpoint: OutPoint },
+    #[error("transaction {index} spends {outpoint}, which is a locked withdrawal")]
+    SpendsWithdrawal { index: usize, outpoint: OutPoint },
+    #[error("transaction {index} is not authorized")]
+    Authorization {
+        index: usize,
+        source: AuthorizationError,
+    },
+    #[error("transaction {index} is invalid")]
+    Transaction {
+        index: usize,
+        source: TransactionError,
+    },
+}
+
+const MAX_OUTPUTS: usize = u8::MAX as usize + 1;
+
+impl Block {
+    /// Check every consensus rule for a block that builds on `prev`, against
+    /// the unspent outputs from before the block.
+    pub fn validate(
+        &self,
+        prev: &Header,
+        utxos: &impl UtxoView,
+        params: &ConsensusParams,
+    ) -> Result<()> {
+        let expected = prev.hash()?;
+        if self.header.prev_side_block_hash != expected {
+            return Err(BlockValidationError::WrongPrevHash {
+                expected,
+                actual: self.header.prev_side_block_hash,
+            }
+            .into());
+        }
+        self.validate_contents(utxos, params)
+    }
+
+    /// Same as `validate`, except for the link to the previous header, which
+    /// the first block doesn't have.
+    ///
+    /// Inputs must spend outputs that existed before the block, so no
+    /// transaction can spend an output of another in the same block.
+    pub fn validate_contents(&self, utxos: &impl UtxoView, params: &ConsensusParams) -> Result<()> {
+        let Self {
+            header,
+            coinbase,
+            transactions,
+        } = self;
+        let size = self.serialized_size()?;
+        if size > params.block_size_limit {
+            return Err(BlockValidationError::TooLarge {
+                size,
+                limit: params.block_size_limit,
+            }
+            .into());
+        }
+        if header.merkle_root != Header::compute_authorized_merkle_root(coinbase, transactions)? {
+            return Err(BlockValidationError::WrongMerkleRoot.into());
+        }
+        if header.witness_merkle_root
+            != Header::compute_witness_merkle_root(coinbase, transactions)?
+        {
+            return Err(BlockValidationError::WrongWitnessMerkleRoot.into());
+        }
+        if coinbase.len() > MAX_OUTPUTS {
+            return Err(BlockValidationError::TooManyCoinbaseOutputs(coinbase.len()).into());
+        }
+
+        let mut spent = HashSet::new();
+        let mut fees = Vec::with_capacity(transactions.len());
+        for (index, transaction) in transactions.iter().enumerate() {
+            let outputs = transaction.transaction.outputs.len();
+            if outputs > MAX_OUTPUTS {
+                return Err(BlockValidationError::TooManyOutputs { index, outputs }.into());
+            }
+            let mut spent_outputs = Vec::with_capacity(transaction.transaction.inputs.len());
+            for outpoint in &transaction.transaction.inputs {
+                if !spent.insert(outpoint) {
+                    return Err(BlockValidationError::DoubleSpend {
+                        index,
+                        outpoint: outpoint.clone(),
+                    }
+                    .into());
+                }
+                let output =
+                    utxos
+                        .get_utxo(outpoint)
+                        .ok_or_else(|| BlockValidationError::MissingInput {
+                            index,
+                            outpoint: outpoint.clone(),
+                        })?;
+                if let Output::Withdrawal { .. } = output {
+                    return Err(BlockValidationError::SpendsWithdrawal {
+                        index,
+                        outpoint: outpoint.clone(),
+                    }
+                    .into());
+                }
+                spent_outputs.push(output);
+            }
+            verify_authorizations(transaction, params.sidechain_number, &spent_outputs).map_err(
+                |err| match err {
+                    Error::Authorization(source) => {
+                        BlockValidationError::Authorization { index, source }.into()
+                    }
+                    err => err,
+                },
+            )?;
+            let fee = transaction
+                .transaction
+                .fee(utxos)
+                .map_err(|source| BlockValidationError::Transaction { index, source })?;
+            fees.push(Ok(fee));
+        }
+
+        let value = checked_sum(coinbase.iter().map(Output::checked_total_value))
+            .map_err(BlockValidationError::CoinbaseValue)?;
+        let fees = checked_sum(fees).map_err(BlockValidationError::Fees)?;
+        // Past MAX_MONEY the coinbase value check above fails anyway.
+        let limit = fees.saturating_add(params.block_subsidy);
+        if value > limit {
+            return Err(BlockValidationError::CoinbaseTooLarge { value, limit }.into());
+        }
+        Ok(())
+    }
+}